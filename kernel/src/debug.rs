@@ -1,10 +1,34 @@
-//! Contains a debug console implementation that uses the legacy SBI extension.
+//! Contains a debug console implementation that uses the SBI debug console
+//! extension, or the legacy SBI extension on firmware which lacks it.
+//!
+//! User mode may read from the console through a console capability.
 
 use {
-    crate::sbi::legacy::console_put,
-    ::core::fmt::{Arguments, Result, Write},
+    crate::{
+        frame::Idx,
+        layout::image_phys_addr,
+        sbi::{base, dbcn, legacy},
+    },
+    ::core::{
+        fmt::{Arguments, Result, Write},
+        ptr::addr_of_mut,
+        sync::atomic::{AtomicBool, Ordering::Relaxed},
+    },
 };
 
+static DBCN: AtomicBool = AtomicBool::new(false);
+
+/// Determine which SBI extension to write to the debug console with. Until
+/// this is called we use the legacy extension.
+pub fn probe() {
+    if let base::ExtAvail::Available(_) = base::probe_extension(dbcn::EID) {
+        DBCN.store(true, Relaxed);
+    } else {
+        let legacy_console_put = base::probe_extension(legacy::CONSOLE_PUT_EID);
+        assert!(matches!(legacy_console_put, base::ExtAvail::Available(_)));
+    }
+}
+
 /// Print a formatted error message to the debug console.
 #[macro_export]
 macro_rules! kernel {
@@ -36,16 +60,83 @@ pub struct Console;
 
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> Result {
-        for b in s.bytes() {
-            console_put(b)
+        if DBCN.load(Relaxed) {
+            // The SBI wants a physical address, and almost everything we
+            // print lives in the kernel image, so write it in bulk.
+            let start = s.as_ptr().addr();
+            let end = start + s.len().saturating_sub(1);
+            if let (Some(mut addr), Some(_)) = (image_phys_addr(start), image_phys_addr(end)) {
+                let mut len = s.len();
+                while len != 0 {
+                    // SAFETY: The bytes are borrowed from `s`.
+                    let written = unsafe { dbcn::console_write(len, addr) }
+                        .expect("Console writes should never fail.");
+                    addr += written;
+                    len -= written;
+                }
+            } else {
+                for b in s.bytes() {
+                    dbcn::console_write_byte(b).expect("Console writes should never fail.");
+                }
+            }
+        } else {
+            for b in s.bytes() {
+                legacy::console_put(b)
+            }
         }
         Ok(())
     }
 }
 
 impl Console {
+    /// Read a byte from the console, if one is available.
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if DBCN.load(Relaxed) {
+            let mut b = 0x0u8;
+            let addr = image_phys_addr(addr_of_mut!(b).addr())
+                .expect("The kernel stack should be in the kernel image.");
+            // SAFETY: The byte is ours to write.
+            let read =
+                unsafe { dbcn::console_read(0x1, addr) }.expect("Console reads should never fail.");
+            (read != 0x0).then(|| b)
+        } else {
+            legacy::console_get()
+        }
+    }
+
     pub fn log(&mut self, level: &str, args: Arguments, file: &str, line: u32) {
         writeln!(self, "[{}]\t{} ({}:{})", level, args, file, line)
             .expect("Console writes should never fail.");
     }
 }
+
+/// A capability to read from the debug console, and to shut down the machine.
+///
+/// There is only the one console, so the capability refers to no frame. Reads
+/// only ever poll, since the SBI gives us no interrupt to wait on.
+#[derive(Clone, Debug)]
+pub struct ConsoleCap(());
+
+impl ConsoleCap {
+    /// # Safety
+    /// The console may only be read, and the machine shut down, by those the
+    /// kernel trusts with it.
+    pub unsafe fn new() -> Self {
+        Self(())
+    }
+
+    pub fn read_byte(&self) -> Option<u8> {
+        Console.read_byte()
+    }
+
+    pub fn into_frame_number(self) -> Idx {
+        Idx::DANGLING
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(_frame_number: Idx) -> Self {
+        Self(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Endpoints, through which threads exchange messages synchronously.
+//!
+//! A message is the six registers `a2` through `a7`. Sending a message blocks
+//! the sender until a thread receives it, and receiving blocks the receiver
+//! until a thread sends. Whichever arrives first waits in the endpoint's queue,
+//! off the run queue, and the second copies the message between the two and
+//! puts the first back on the run queue.
+//!
+//! Calling is sending and then waiting for a reply. The receiver of a call
+//! holds a one-shot reply capability to its caller, which it uses up by
+//! replying, and which is discarded if it receives another call first. A
+//! caller whose reply is discarded is woken with an error instead.
+//!
+//! A badged endpoint queues on the endpoint it was badged from, and delivers
+//! its badge to the receiver, so that the receiver can tell its senders apart.
+
+use crate::{
+    frame::{Idx, NormalArc},
+    sync::{Token, TokenCell},
+    syscall::{Error, Result},
+    thread::{IpcState, ThreadCap},
+};
+
+/// The number of message registers, `a2` through `a7`.
+pub const MESSAGE_LEN: usize = 0x6;
+
+impl EndpointCap {
+    pub fn new(frame_number: Idx) -> Option<Self> {
+        let endpoint = Endpoint {
+            target: None,
+            badge: 0x0,
+            head: None,
+            tail: None,
+            receiving: false,
+        };
+        let endpoint = TokenCell::new(endpoint);
+        let endpoint = NormalArc::new(frame_number, endpoint)?;
+        Some(Self { endpoint })
+    }
+
+    /// Create an endpoint which queues on this one, and delivers `badge`.
+    ///
+    /// Only unbadged endpoints can be badged, and the badge must be nonzero.
+    pub fn badged(&self, token: &Token, frame_number: Idx, badge: usize) -> Result<Self> {
+        if self.badge(token) != 0x0 || badge == 0x0 {
+            return Err(Error::InvalidArgument);
+        }
+        let endpoint = Endpoint {
+            target: Some(self.clone()),
+            badge,
+            head: None,
+            tail: None,
+            receiving: false,
+        };
+        let endpoint = TokenCell::new(endpoint);
+        let endpoint = NormalArc::new(frame_number, endpoint).ok_or(Error::InvalidFrame)?;
+        Ok(Self { endpoint })
+    }
+
+    pub fn badge(&self, token: &Token) -> usize {
+        self.endpoint.borrow(token).badge
+    }
+
+    /// Send the message in `thread`'s registers, and if `call`, wait for a
+    /// reply.
+    ///
+    /// Returns [`Error::Waiting`] if `thread` must wait, in which case it
+    /// will be woken with its result.
+    pub fn send(&self, token: &mut Token, thread: &ThreadCap, call: bool) -> Result<usize> {
+        let badge = self.badge(token);
+        let endpoint = self.unbadged(token);
+        if let Some(receiver) = endpoint.pop(token, true) {
+            copy_message(token, thread, &receiver);
+            wake(token, &receiver, Ok(badge));
+            if !call {
+                return Ok(0x0);
+            }
+            thread.set_ipc_state(token, IpcState::AwaitingReply);
+            set_reply(token, &receiver, thread.clone());
+        } else {
+            thread.set_ipc_state(token, IpcState::Sending { badge, call });
+            endpoint.push(token, thread.clone(), false);
+        }
+        Err(Error::Waiting)
+    }
+
+    /// Receive a message into `thread`'s registers, returning its badge.
+    ///
+    /// Returns [`Error::Waiting`] if `thread` must wait, in which case it
+    /// will be woken with its result.
+    pub fn recv(&self, token: &mut Token, thread: &ThreadCap) -> Result<usize> {
+        let endpoint = self.unbadged(token);
+        let sender = if let Some(sender) = endpoint.pop(token, false) {
+            sender
+        } else {
+            thread.set_ipc_state(token, IpcState::Receiving);
+            endpoint.push(token, thread.clone(), true);
+            return Err(Error::Waiting);
+        };
+        let (badge, call) = match sender.ipc_state(token) {
+            IpcState::Sending { badge, call } => (badge, call),
+            _ => unreachable!("Only sending threads are queued as senders."),
+        };
+        copy_message(token, &sender, thread);
+        if call {
+            sender.set_ipc_state(token, IpcState::AwaitingReply);
+            set_reply(token, thread, sender);
+        } else {
+            wake(token, &sender, Ok(0x0));
+        }
+        Ok(badge)
+    }
+
+    /// The endpoint whose queue this one uses.
+    fn unbadged(&self, token: &Token) -> Self {
+        let endpoint = self.endpoint.borrow(token);
+        endpoint.target.clone().unwrap_or_else(|| self.clone())
+    }
+
+    /// Queue `thread` to wait, as a receiver if `receiving` and otherwise as a
+    /// sender.
+    ///
+    /// Every thread in the queue must be waiting for the same thing.
+    fn push(&self, token: &mut Token, thread: ThreadCap, receiving: bool) {
+        let endpoint = self.endpoint.borrow_mut(token);
+        debug_assert!(endpoint.head.is_none() || endpoint.receiving == receiving);
+        endpoint.receiving = receiving;
+        let tail = endpoint.tail.replace(thread.clone());
+        if let Some(tail) = tail {
+            tail.set_next(token, thread);
+        } else {
+            self.endpoint.borrow_mut(token).head = Some(thread);
+        }
+    }
+
+    /// Take the thread at the front of the queue, if it's a receiver when
+    /// `receiving` and a sender otherwise.
+    fn pop(&self, token: &mut Token, receiving: bool) -> Option<ThreadCap> {
+        let endpoint = self.endpoint.borrow_mut(token);
+        if endpoint.receiving != receiving {
+            return None;
+        }
+        let head = endpoint.head.take()?;
+        let next = head.take_next(token);
+        let endpoint = self.endpoint.borrow_mut(token);
+        if next.is_none() {
+            endpoint.tail = None;
+        }
+        endpoint.head = next;
+        Some(head)
+    }
+
+    pub fn into_frame_number(self) -> Idx {
+        self.endpoint.into_raw()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let endpoint = unsafe { NormalArc::from_raw(frame_number) };
+        Self { endpoint }
+    }
+}
+
+#[derive(Clone)]
+pub struct EndpointCap {
+    endpoint: NormalArc<TokenCell<Endpoint>>,
+}
+
+struct Endpoint {
+    /// The endpoint this one queues on, if it's badged.
+    target: Option<EndpointCap>,
+    /// The word delivered to the receiver, or zero if the endpoint is
+    /// unbadged.
+    badge: usize,
+    /// The threads waiting on the endpoint, which are always empty if the
+    /// endpoint is badged.
+    head: Option<ThreadCap>,
+    tail: Option<ThreadCap>,
+    /// Whether the waiting threads are receivers rather than senders.
+    receiving: bool,
+}
+
+/// Reply to `thread`'s caller with the message in `thread`'s registers.
+pub fn reply(token: &mut Token, thread: &ThreadCap) -> Result<()> {
+    let caller = thread.take_reply(token).ok_or(Error::InvalidState)?;
+    copy_message(token, thread, &caller);
+    wake(token, &caller, Ok(0x0));
+    Ok(())
+}
+
+/// Give `thread` the one-shot reply capability to `caller`, discarding any it
+/// already holds.
+fn set_reply(token: &mut Token, thread: &ThreadCap, caller: ThreadCap) {
+    if let Some(abandoned) = thread.set_reply(token, caller) {
+        wake(token, &abandoned, Err(Error::InvalidState));
+    }
+}
+
+fn copy_message(token: &mut Token, from: &ThreadCap, to: &ThreadCap) {
+    let context = from
+        .context(token)
+        .expect("A waiting thread should have a context.");
+    let mut message = [0x0; MESSAGE_LEN];
+    message.copy_from_slice(&context.a[0x2..]);
+    let context = to
+        .context_mut(token)
+        .expect("A waiting thread should have a context.");
+    context.a[0x2..].copy_from_slice(&message);
+}
+
+/// Deliver `result` to the waiting `thread` and put it back on the run queue.
+fn wake(token: &mut Token, thread: &ThreadCap, result: Result<usize>) {
+    thread.set_ipc_state(token, IpcState::Running);
+    thread.wake(token, result);
+}
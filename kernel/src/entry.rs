@@ -1,7 +1,8 @@
 //! Contains all possible entrypoints into the kernel.
 //!
 //! The kernel can be entered in two ways:
-//! 1. On boot, SBI jumps into the kernel on the boot hart.
+//! 1. On boot, SBI jumps into the kernel on the boot hart, which then asks SBI
+//!    to start the other harts at their own entrypoint.
 //! 2. On a trap, the CPU jumps to the trap handler.
 //!
 //! In both cases, we need to do a bit of work in assembly before it's safe
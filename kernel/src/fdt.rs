@@ -0,0 +1,347 @@
+//! A minimal parser for the flattened device tree the SBI hands us on boot.
+//!
+//! We only need to know which ranges of physical addresses are memory, which
+//! of those are reserved, and which belong to devices, along with where a few
+//! devices the kernel drives itself live. So rather than build a tree we walk
+//! the structure block and report what we're after as we find it.
+//!
+//! See the [devicetree specification][0] for the format.
+//!
+//! [0]: https://www.devicetree.org/specifications/
+
+use ::core::{mem::size_of, ops::Range, slice};
+
+const MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 0x28;
+
+const BEGIN_NODE: u32 = 0x1;
+const END_NODE: u32 = 0x2;
+const PROP: u32 = 0x3;
+const NOP: u32 = 0x4;
+const END: u32 = 0x9;
+
+/// The deepest nesting of nodes we can walk.
+const MAX_DEPTH: usize = 0x10;
+
+/// The `compatible` strings of the interrupt controllers we can drive.
+const PLIC_COMPATIBLE: [&[u8]; 2] = [b"riscv,plic0", b"sifive,plic-1.0.0"];
+
+/// A flattened device tree blob.
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+}
+
+/// What a region of physical addresses holds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegionKind {
+    /// Memory, which may be handed out unless it's also reserved.
+    Memory,
+    /// Memory which is in use by the firmware, or otherwise off limits.
+    Reserved,
+    /// The registers of a device.
+    Device,
+}
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub addrs: Range<usize>,
+}
+
+/// A platform-level interrupt controller.
+#[derive(Clone, Debug)]
+pub struct Plic {
+    /// The physical addresses of its registers.
+    pub addrs: Range<usize>,
+    /// The number of interrupt sources it has, not counting the nonexistent
+    /// source zero.
+    pub source_count: usize,
+}
+
+/// A token of the structure block.
+#[derive(Clone, Copy)]
+enum Event<'a> {
+    BeginNode(&'a [u8]),
+    EndNode,
+    Prop(&'a [u8], &'a [u8]),
+}
+
+/// The state of a node which is still open as we walk the structure block.
+#[derive(Clone, Copy)]
+struct Scope {
+    /// What the node's `reg` property describes.
+    kind: RegionKind,
+    /// What the `reg` properties of the node's children describe.
+    child_kind: RegionKind,
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl Scope {
+    // The specification's defaults for a node without `#address-cells` or
+    // `#size-cells`.
+    const DEFAULT: Self = Self {
+        kind: RegionKind::Device,
+        child_kind: RegionKind::Device,
+        address_cells: 0x2,
+        size_cells: 0x1,
+    };
+}
+
+impl<'a> Fdt<'a> {
+    /// # Safety
+    /// `ptr` must point to a flattened device tree which remains valid and
+    /// unmodified for `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Option<Self> {
+        // SAFETY: The caller ensures there's at least a header to read.
+        let header = unsafe { slice::from_raw_parts(ptr, HEADER_SIZE) };
+        if be_u32(header, 0x0)? != MAGIC {
+            return None;
+        }
+        let total_size = be_u32(header, 0x4)? as usize;
+        // SAFETY: The caller ensures the whole blob is valid.
+        let blob = unsafe { slice::from_raw_parts(ptr, total_size) };
+        Some(Self { blob })
+    }
+
+    /// The number of bytes in the blob.
+    pub fn size(&self) -> usize {
+        self.blob.len()
+    }
+
+    /// Call `f` with every region of physical addresses the device tree
+    /// describes, returning `None` if the device tree is malformed.
+    ///
+    /// Memory comes from the `/memory` nodes, reserved memory from the memory
+    /// reservation block, the children of `/reserved-memory` and the initrd
+    /// given by `/chosen`, and devices from the `reg` properties of every
+    /// other node whose addresses have a size. We assume that every bus maps
+    /// its children's addresses one to one, as is the case on QEMU's virt
+    /// machine, and skip addresses or sizes which don't fit in a `usize`.
+    pub fn for_each_region(&self, mut f: impl FnMut(Region)) -> Option<()> {
+        let blob = self.blob;
+
+        let mut offset = be_u32(blob, 0x10)? as usize;
+        loop {
+            let addr = be_u64(blob, offset)? as usize;
+            let size = be_u64(blob, offset + size_of::<u64>())? as usize;
+            offset += 0x2 * size_of::<u64>();
+            if addr == 0x0 && size == 0x0 {
+                break;
+            }
+            f(Region {
+                kind: RegionKind::Reserved,
+                addrs: addr..addr.checked_add(size)?,
+            });
+        }
+
+        // The scope of each open node, indexed by its depth. The root node is
+        // at depth one, so that depth zero provides its defaults.
+        let mut scopes = [Scope::DEFAULT; MAX_DEPTH];
+        let mut depth = 0x0;
+        let mut in_chosen = false;
+        let (mut initrd_start, mut initrd_end) = (None, None);
+        self.walk(|event| {
+            match event {
+                Event::BeginNode(name) => {
+                    let parent = *scopes.get(depth)?;
+                    depth += 0x1;
+                    let scope = scopes.get_mut(depth)?;
+                    *scope = Scope {
+                        kind: parent.child_kind,
+                        ..Scope::DEFAULT
+                    };
+                    if depth == 0x2 && is_named(name, b"memory") {
+                        scope.kind = RegionKind::Memory;
+                    }
+                    if depth == 0x2 && is_named(name, b"reserved-memory") {
+                        scope.child_kind = RegionKind::Reserved;
+                    }
+                    if depth == 0x2 && name == b"chosen" {
+                        in_chosen = true;
+                    }
+                }
+                Event::EndNode => {
+                    if depth == 0x2 {
+                        in_chosen = false;
+                    }
+                    depth = depth.checked_sub(0x1)?;
+                }
+                Event::Prop(name, value) => {
+                    match name {
+                        b"#address-cells" => scopes.get_mut(depth)?.address_cells = cells(value)?,
+                        b"#size-cells" => scopes.get_mut(depth)?.size_cells = cells(value)?,
+                        b"reg" if depth >= 0x2 => {
+                            let kind = scopes[depth].kind;
+                            let parent = scopes[depth - 0x1];
+                            for_each_reg(value, parent, |addrs| f(Region { kind, addrs }))?;
+                        }
+                        b"linux,initrd-start" if in_chosen => initrd_start = cells_value(value),
+                        b"linux,initrd-end" if in_chosen => initrd_end = cells_value(value),
+                        _ => {}
+                    }
+                    if let (Some(start), Some(end)) = (initrd_start, initrd_end) {
+                        f(Region {
+                            kind: RegionKind::Reserved,
+                            addrs: start..end,
+                        });
+                        (initrd_start, initrd_end) = (None, None);
+                    }
+                }
+            }
+            Some(())
+        })
+    }
+
+    /// Find the first platform-level interrupt controller, returning `None` if
+    /// there is none or the device tree is malformed.
+    ///
+    /// As with [`Self::for_each_region`], we assume that the controller's
+    /// registers are mapped one to one.
+    pub fn plic(&self) -> Option<Plic> {
+        let mut scopes = [Scope::DEFAULT; MAX_DEPTH];
+        let mut depth = 0x0;
+        // What we've seen of the node whose properties we're walking. A node's
+        // properties all come before its children, so the node is complete by
+        // the time the next node begins or it ends.
+        let (mut is_plic, mut addrs, mut source_count) = (false, None, None);
+        let mut plic = None;
+        self.walk(|event| {
+            if let Event::BeginNode(_) | Event::EndNode = event {
+                if let (true, Some(addrs), Some(source_count), None) =
+                    (is_plic, addrs.clone(), source_count, &plic)
+                {
+                    plic = Some(Plic {
+                        addrs,
+                        source_count,
+                    });
+                }
+                (is_plic, addrs, source_count) = (false, None, None);
+            }
+            match event {
+                Event::BeginNode(_) => {
+                    depth += 0x1;
+                    *scopes.get_mut(depth)? = Scope::DEFAULT;
+                }
+                Event::EndNode => depth = depth.checked_sub(0x1)?,
+                Event::Prop(name, value) => match name {
+                    b"#address-cells" => scopes.get_mut(depth)?.address_cells = cells(value)?,
+                    b"#size-cells" => scopes.get_mut(depth)?.size_cells = cells(value)?,
+                    b"compatible" => {
+                        is_plic = value
+                            .split(|&b| b == 0x0)
+                            .any(|compatible| PLIC_COMPATIBLE.contains(&compatible));
+                    }
+                    b"reg" if depth >= 0x2 => {
+                        let parent = scopes[depth - 0x1];
+                        for_each_reg(value, parent, |reg| {
+                            addrs.get_or_insert(reg);
+                        })?;
+                    }
+                    b"riscv,ndev" => source_count = Some(cells(value)?),
+                    _ => {}
+                },
+            }
+            Some(())
+        })?;
+        plic
+    }
+
+    /// Call `f` with each token of the structure block, stopping early if it
+    /// returns `None`.
+    fn walk(&self, mut f: impl FnMut(Event) -> Option<()>) -> Option<()> {
+        let blob = self.blob;
+        let strings = be_u32(blob, 0xc)? as usize;
+        let mut offset = be_u32(blob, 0x8)? as usize;
+        loop {
+            let token = be_u32(blob, offset)?;
+            offset += size_of::<u32>();
+            match token {
+                BEGIN_NODE => {
+                    let name = c_str(blob, offset)?;
+                    offset += align_up(name.len() + 0x1);
+                    f(Event::BeginNode(name))?;
+                }
+                END_NODE => f(Event::EndNode)?,
+                PROP => {
+                    let len = be_u32(blob, offset)? as usize;
+                    let name_offset = be_u32(blob, offset + size_of::<u32>())? as usize;
+                    offset += 0x2 * size_of::<u32>();
+                    let name = c_str(blob, strings + name_offset)?;
+                    let value = blob.get(offset..offset + len)?;
+                    offset += align_up(len);
+                    f(Event::Prop(name, value))?;
+                }
+                NOP => {}
+                END => return Some(()),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Call `f` with each range of addresses in the `reg` property `value`, whose
+/// cells are given by the scope of the node's `parent`.
+fn for_each_reg(value: &[u8], parent: Scope, mut f: impl FnMut(Range<usize>)) -> Option<()> {
+    let Scope {
+        address_cells,
+        size_cells,
+        ..
+    } = parent;
+    // Registers without sizes, like those of harts, aren't addresses.
+    if size_cells == 0x0 {
+        return Some(());
+    }
+    let entry_size = (address_cells + size_cells) * size_of::<u32>();
+    for entry in value.chunks_exact(entry_size) {
+        let (addr, size) = entry.split_at(address_cells * size_of::<u32>());
+        if let (Some(addr), Some(size)) = (cells_value(addr), cells_value(size)) {
+            f(addr..addr.checked_add(size)?);
+        }
+    }
+    Some(())
+}
+
+/// Decode a property holding a single cell.
+fn cells(value: &[u8]) -> Option<usize> {
+    Some(u32::from_be_bytes(value.try_into().ok()?) as usize)
+}
+
+/// Decode a number spread across big-endian cells, if it fits in a `usize`.
+fn cells_value(cells: &[u8]) -> Option<usize> {
+    if cells.len() > size_of::<usize>() || cells.len() % size_of::<u32>() != 0x0 {
+        return None;
+    }
+    Some(
+        cells
+            .iter()
+            .fold(0x0, |value, &b| (value << 0x8) | b as usize),
+    )
+}
+
+/// Whether the node `name` is `base`, with or without a unit address.
+fn is_named(name: &[u8], base: &[u8]) -> bool {
+    name == base || (name.starts_with(base) && name.get(base.len()) == Some(&b'@'))
+}
+
+fn be_u32(blob: &[u8], offset: usize) -> Option<u32> {
+    let bytes = blob.get(offset..offset + size_of::<u32>())?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn be_u64(blob: &[u8], offset: usize) -> Option<u64> {
+    let bytes = blob.get(offset..offset + size_of::<u64>())?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// The bytes of the null-terminated string at `offset`, excluding the null.
+fn c_str(blob: &[u8], offset: usize) -> Option<&[u8]> {
+    let bytes = blob.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0x0)?;
+    Some(&bytes[..len])
+}
+
+/// Round `len` up to the alignment of the structure block's tokens.
+fn align_up(len: usize) -> usize {
+    (len + size_of::<u32>() - 0x1) & !(size_of::<u32>() - 0x1)
+}
@@ -6,8 +6,9 @@
 
 use {
     crate::{
-        machine::{FRAME_COUNT, L0_FRAME_SIZE},
+        machine::{L0_FRAME_SIZE, MAX_FRAME_COUNT},
         ptr::MaybeDangling,
+        sync::RacyCell,
     },
     ::core::{
         any::type_name,
@@ -16,7 +17,8 @@ use {
         marker::PhantomData,
         mem::forget,
         mem::{align_of, size_of},
-        ops::Deref,
+        ops::{Deref, Range},
+        slice,
         sync::atomic::{
             Ordering::{Acquire, Relaxed, Release},
             {AtomicPtr, AtomicU32, AtomicU8},
@@ -29,19 +31,29 @@ use {
 pub struct Idx(u32);
 
 impl Idx {
-    const MAX: u32 = crate::machine::FRAME_COUNT as u32 - 1;
-    const FRAME_COUNT_CHECK: () = assert!(crate::machine::FRAME_COUNT <= u32::MAX as usize);
+    /// A frame number which refers to no frame, for capabilities to objects
+    /// which don't live in frames. Its metadata must never be accessed.
+    pub const DANGLING: Self = Self(0x0);
 
-    pub const fn from_raw(value: usize) -> Option<Self> {
+    // Buried frames offset the next frame number by two in a reference count.
+    const FRAME_COUNT_CHECK: () = assert!(MAX_FRAME_COUNT + 0x2 <= u32::MAX as usize);
+
+    /// Validate that `value` is the number of a frame we track.
+    pub fn from_raw(value: usize) -> Option<Self> {
         // Force evaluation of the above static assertion.
         forget(Self::FRAME_COUNT_CHECK);
 
-        if value > Self::MAX as usize {
-            return None;
-        }
+        segment(value)?;
         Some(Self(value as u32))
     }
 
+    /// # Safety
+    /// The result may only be used to reach a frame if `value` was returned
+    /// by `into_raw` on a previous `Idx`.
+    pub const unsafe fn from_raw_unchecked(value: usize) -> Self {
+        Self(value as u32)
+    }
+
     pub const fn into_raw(&self) -> usize {
         self.0 as usize
     }
@@ -67,6 +79,48 @@ impl<T> Arc<T, NormalPolicy> {
         // dropped.
         unsafe { frame.as_ref() }
     }
+
+    /// Set aside the only reference to the frame, to be destroyed later,
+    /// handing it back if it isn't the only one.
+    ///
+    /// Nothing else can reach the frame while it's set aside, so its reference
+    /// count holds `next` instead. This lets frames waiting to be destroyed
+    /// form a list without any storage of their own.
+    pub fn bury(self, next: Option<Idx>) -> Result<Idx, Self> {
+        let (frame_kind, ref_count, _) = Self::frame(self.idx);
+        debug_assert_eq!(frame_kind, FrameKind::Normal);
+        // Any count but zero keeps the frame from being allocated.
+        let link = next.map_or(0x1, |next| next.0 + 0x2);
+        // ORDERING: Any previous access to the frame must happen strictly
+        // before the destruction.
+        if ref_count
+            .compare_exchange(2, link, Acquire, Relaxed)
+            .is_err()
+        {
+            return Err(self);
+        }
+        let idx = self.idx;
+        forget(self);
+        Ok(idx)
+    }
+
+    /// Take back the reference to a frame which was set aside, along with the
+    /// next frame it was buried with.
+    ///
+    /// # Safety
+    /// `idx` must have been returned from a previous call to `bury` of the
+    /// same type, and not yet exhumed.
+    pub unsafe fn exhume(idx: Idx) -> (Self, Option<Idx>) {
+        let (_, ref_count, _) = Self::frame(idx);
+        let link = ref_count.swap(2, Relaxed);
+        let next = (link != 0x1).then(|| Idx(link - 0x2));
+        let arc = Self {
+            idx,
+            _t: PhantomData,
+            _policy: PhantomData,
+        };
+        (arc, next)
+    }
 }
 
 impl<T: Copy> Arc<T, InternalPolicy> {
@@ -96,7 +150,8 @@ impl<T, Policy: sealed::ArcPolicy> Arc<T, Policy> {
 
         let (frame_kind, ref_count, frame) = Self::frame(idx);
 
-        if frame_kind != expected_frame_kind {
+        // Reserved frames are never handed out, whatever the policy.
+        if frame_kind == FrameKind::Reserved || frame_kind != expected_frame_kind {
             return None;
         }
 
@@ -119,12 +174,15 @@ impl<T, Policy: sealed::ArcPolicy> Arc<T, Policy> {
         })
     }
 
+    /// The frame the reference is to, which stays referenced.
+    pub fn idx(&self) -> Idx {
+        self.idx
+    }
+
     fn frame(idx: Idx) -> (FrameKind, &'static AtomicU32, MaybeDangling<T>) {
-        let ref_count = &REF_COUNTS[idx.into_raw()];
-        let frame_kind = FRAME_KINDS[idx.into_raw()]
-            .load(Relaxed)
-            .try_into()
-            .unwrap();
+        let (frame_kinds, ref_counts) = metadata(idx.into_raw(), 1).unwrap();
+        let ref_count = &ref_counts[0];
+        let frame_kind = frame_kinds[0].load(Relaxed).try_into().unwrap();
         let frame_mapping_addr = FRAME_MAPPING_ADDR.load(Relaxed);
         assert!(!frame_mapping_addr.is_null());
         let addr = frame_mapping_addr.map_addr(|addr| addr + idx.into_raw() * L0_FRAME_SIZE);
@@ -186,14 +244,16 @@ impl<T, Policy: sealed::ArcPolicy> Drop for Arc<T, Policy> {
         debug_assert!(ref_count.load(Relaxed) > 1);
         // ORDERING: Any previous access to the frame must happen strictly
         // before the destruction.
-        if ref_count.fetch_sub(1, Release) == 1 {
+        // The stored count is offset by one, so that a count of one marks a
+        // frame that is being constructed or destroyed.
+        if ref_count.fetch_sub(1, Release) == 2 {
             // ORDERING: Any previous access to the frame must happen strictly
             // before the destruction.
             ref_count.load(Acquire);
             let frame = frame.as_ptr();
-            // SAFETY: There exist no references to this frame because the
-            // reference count is zero. The frame's lifetime extends until
-            // the pointer is dropped.
+            // SAFETY: There exist no references to this frame because we just
+            // released the last one. The frame's lifetime extends until the
+            // reference count is reset to zero.
             unsafe { frame.drop_in_place() };
             // ORDERING: The destruction must happen strictly before any future
             // construction.
@@ -239,15 +299,211 @@ pub type InternalArc<T> = Arc<T, InternalPolicy>;
 pub type NormalArc<T> = Arc<T, NormalPolicy>;
 pub type ExternalArc<T> = Arc<T, ExternalPolicy>;
 
-// TODO: Allow multiple levels of frames to be allocated.
-// TODO: Dynamically allocate this table with the actual valid range of
-// physical addresses so we don't bloat the kernel binary with a massive table.
+/// A reference counted span of `LEN` contiguous frames of normal memory,
+/// aligned to its length, which backs a superpage.
+///
+/// The span is accounted for by the reference count of its first frame. Its
+/// other frames are held at a count of one for as long as the span exists, so
+/// that they can't be allocated on their own.
+pub struct SpanArc<const LEN: usize> {
+    idx: Idx,
+}
+
+/// A span which is partly built or partly released.
+///
+/// Only its holder may continue building it, and if it's dropped instead, the
+/// frames which were claimed for it are released.
+#[derive(Debug)]
+pub struct PartialSpan {
+    idx: Idx,
+}
+
+/// Why a span couldn't be built.
+#[derive(Debug)]
+pub enum SpanError {
+    /// The span is partly built or partly released, and building it must be
+    /// continued.
+    Incomplete(PartialSpan),
+    /// The span is unaligned, or one of its frames isn't a free frame of
+    /// normal memory.
+    InvalidFrame,
+}
+
+/// The most frames of a span which are claimed and zeroed, or released, at a
+/// time, so that building a large span doesn't hold up every other hart.
+const SPAN_STEP: usize = 0x200;
+
+// While a span is being built, its first frame's reference count records how
+// many of its frames have been claimed. These counts lie above any count of
+// references, and include the length of the span, so that spans of different
+// lengths starting at the same frame are kept apart.
+const SPAN_BUILDING: u32 = 0x8000_0000;
+const SPAN_RELEASING: u32 = 0x4000_0000;
+const SPAN_CLAIMED_MASK: u32 = 0xff_ffff;
+
+impl<const LEN: usize> SpanArc<LEN> {
+    const LEN_CHECK: () = assert!(LEN.is_power_of_two() && LEN <= SPAN_CLAIMED_MASK as usize);
+    const BUILDING: u32 = SPAN_BUILDING | (LEN.trailing_zeros() << 24);
+
+    /// Build the span starting at `idx`, zeroing its contents.
+    ///
+    /// Each call claims and zeroes at most `SPAN_STEP` of the span's frames,
+    /// returning [`SpanError::Incomplete`] until it's built, and so must be
+    /// repeated with the same `idx` and the [`PartialSpan`] it returned to
+    /// continue. Any other `partial` span is released. If a frame can't be
+    /// claimed, those which were are released in the same way, and the last
+    /// call returns [`SpanError::InvalidFrame`].
+    pub fn new_zeroed(idx: Idx, partial: Option<PartialSpan>) -> Result<Self, SpanError> {
+        // Force evaluation of the above static assertion.
+        forget(Self::LEN_CHECK);
+
+        let start = idx.into_raw();
+        if start % LEN != 0 {
+            return Err(SpanError::InvalidFrame);
+        }
+        let (frame_kinds, ref_counts) = metadata(start, LEN).ok_or(SpanError::InvalidFrame)?;
+        let state = ref_counts[0].load(Relaxed);
+        let continuing = matches!(&partial, Some(partial) if partial.idx == idx)
+            && state & !(SPAN_RELEASING | SPAN_CLAIMED_MASK) == Self::BUILDING;
+        if continuing {
+            forget(partial);
+        } else {
+            // Release any other span before claiming this one's frames, since
+            // they may well be the same.
+            drop(partial);
+        }
+        let claimed = if !continuing {
+            // The first frame is claimed along with the whole span.
+            let frame_kind: FrameKind = frame_kinds[0].load(Relaxed).try_into().unwrap();
+            // ORDERING: Any previous access to the frame must happen strictly
+            // before the construction.
+            if frame_kind != FrameKind::Normal
+                || ref_counts[0]
+                    .compare_exchange(0, Self::BUILDING, Acquire, Relaxed)
+                    .is_err()
+            {
+                return Err(SpanError::InvalidFrame);
+            }
+            0x0
+        } else if state & SPAN_RELEASING != 0x0 {
+            return Self::release_step(idx, ref_counts, (state & SPAN_CLAIMED_MASK) as usize);
+        } else {
+            (state & SPAN_CLAIMED_MASK) as usize
+        };
+
+        let end = (claimed + SPAN_STEP).min(LEN);
+        for frame in claimed.max(0x1)..end {
+            let frame_kind: FrameKind = frame_kinds[frame].load(Relaxed).try_into().unwrap();
+            // ORDERING: Any previous access to the frame must happen strictly
+            // before the construction.
+            if frame_kind != FrameKind::Normal
+                || ref_counts[frame]
+                    .compare_exchange(0, 1, Acquire, Relaxed)
+                    .is_err()
+            {
+                return Self::release_step(idx, ref_counts, frame);
+            }
+        }
+
+        let frame_mapping_addr = FRAME_MAPPING_ADDR.load(Relaxed);
+        assert!(!frame_mapping_addr.is_null());
+        let addr = frame_mapping_addr.map_addr(|addr| addr + (start + claimed) * L0_FRAME_SIZE);
+        // SAFETY: There exist no other references to these frames because we
+        // hold all of their reference counts above zero.
+        unsafe {
+            addr.cast::<u8>()
+                .write_bytes(0x0, (end - claimed) * L0_FRAME_SIZE)
+        };
+        if end < LEN {
+            ref_counts[0].store(Self::BUILDING | end as u32, Relaxed);
+            return Err(SpanError::Incomplete(PartialSpan { idx }));
+        }
+        // ORDERING: We impose no ordering on loads and stores to the frames
+        // themselves since the construction, destruction, and any sending of
+        // this pointer will impose sufficient ordering.
+        ref_counts[0].store(2, Relaxed);
+        Ok(Self { idx })
+    }
+
+    /// Release up to `SPAN_STEP` of the first `claimed` frames of a span which
+    /// couldn't be built.
+    fn release_step(idx: Idx, ref_counts: &[AtomicU32], claimed: usize) -> Result<Self, SpanError> {
+        let rest = claimed.saturating_sub(SPAN_STEP).max(0x1);
+        // ORDERING: The zeroing must happen strictly before any future
+        // construction.
+        for ref_count in ref_counts[rest..claimed].iter().rev() {
+            ref_count.store(0, Release);
+        }
+        if rest > 0x1 {
+            ref_counts[0].store(Self::BUILDING | SPAN_RELEASING | rest as u32, Relaxed);
+            return Err(SpanError::Incomplete(PartialSpan { idx }));
+        }
+        ref_counts[0].store(0, Release);
+        Err(SpanError::InvalidFrame)
+    }
+
+    pub fn into_raw(self) -> Idx {
+        let idx = self.idx;
+        forget(self);
+        idx
+    }
+
+    /// # Safety
+    /// `idx` must have been returned from a previous call to `into_raw`.
+    pub unsafe fn from_raw(idx: Idx) -> Self {
+        Self { idx }
+    }
+}
+
+impl<const LEN: usize> Clone for SpanArc<LEN> {
+    fn clone(&self) -> Self {
+        let (_, ref_counts) = metadata(self.idx.into_raw(), 1).unwrap();
+        let ref_count = &ref_counts[0];
+        debug_assert!(ref_count.load(Relaxed) > 1);
+        let ref_count = ref_count.fetch_add(1, Relaxed);
+        assert!(ref_count < u32::MAX / 2);
+        Self { idx: self.idx }
+    }
+}
+
+impl<const LEN: usize> Drop for SpanArc<LEN> {
+    fn drop(&mut self) {
+        let (_, ref_counts) = metadata(self.idx.into_raw(), LEN).unwrap();
+        debug_assert!(ref_counts[0].load(Relaxed) > 1);
+        // ORDERING: Any previous access to the frames must happen strictly
+        // before the destruction.
+        if ref_counts[0].fetch_sub(1, Release) == 2 {
+            ref_counts[0].load(Acquire);
+            // ORDERING: The destruction must happen strictly before any future
+            // construction.
+            for ref_count in ref_counts.iter().rev() {
+                ref_count.store(0, Release);
+            }
+        }
+    }
+}
+
+impl Drop for PartialSpan {
+    fn drop(&mut self) {
+        let (_, ref_counts) = metadata(self.idx.into_raw(), 1).unwrap();
+        let claimed = (ref_counts[0].load(Relaxed) & SPAN_CLAIMED_MASK) as usize;
+        let (_, ref_counts) = metadata(self.idx.into_raw(), claimed).unwrap();
+        // ORDERING: The zeroing must happen strictly before any future
+        // construction.
+        for ref_count in ref_counts.iter().rev() {
+            ref_count.store(0, Release);
+        }
+    }
+}
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum FrameKind {
     Internal,
     Normal,
     External,
+    /// Memory which belongs to the firmware, the kernel image, or the boot
+    /// payloads, and which must never be referenced by a frame object.
+    Reserved,
 }
 
 impl TryFrom<u8> for FrameKind {
@@ -256,36 +512,169 @@ impl TryFrom<u8> for FrameKind {
         const INTERNAL: u8 = FrameKind::Internal as u8;
         const NORMAL: u8 = FrameKind::Normal as u8;
         const EXTERNAL: u8 = FrameKind::External as u8;
+        const RESERVED: u8 = FrameKind::Reserved as u8;
         let frame_kind = match val {
             INTERNAL => FrameKind::Internal,
             NORMAL => FrameKind::Normal,
             EXTERNAL => FrameKind::External,
+            RESERVED => FrameKind::Reserved,
             _ => return Err(()),
         };
         Ok(frame_kind)
     }
 }
 
-static FRAME_KINDS: [AtomicU8; FRAME_COUNT] = {
-    const INIT: AtomicU8 = AtomicU8::new(FrameKind::Internal as u8);
-    [INIT; FRAME_COUNT]
-};
-static REF_COUNTS: [AtomicU32; FRAME_COUNT] = {
-    const INIT: AtomicU32 = AtomicU32::new(0);
-    [INIT; FRAME_COUNT]
-};
+/// The most discontiguous ranges of frames we can track.
+pub const MAX_SEGMENTS: usize = 0x40;
+
+/// A contiguous range of frames we track, along with their metadata.
+#[derive(Clone, Copy)]
+struct Segment {
+    /// The number of the range's first frame.
+    start: usize,
+    frame_kinds: &'static [AtomicU8],
+    ref_counts: &'static [AtomicU32],
+}
+
+impl Segment {
+    const EMPTY: Self = Self {
+        start: 0x0,
+        frame_kinds: &[],
+        ref_counts: &[],
+    };
+
+    fn frames(&self) -> Range<usize> {
+        self.start..self.start + self.frame_kinds.len()
+    }
+}
+
+struct Segments {
+    segments: [Segment; MAX_SEGMENTS],
+    len: usize,
+}
+
+static SEGMENTS: RacyCell<Segments> = RacyCell::new(Segments {
+    segments: [Segment::EMPTY; MAX_SEGMENTS],
+    len: 0x0,
+});
 static FRAME_MAPPING_ADDR: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
 
 pub unsafe fn set_frame_mapping_addr(addr: *mut ()) {
     FRAME_MAPPING_ADDR.store(addr, Relaxed);
 }
 
+/// The number of frames needed to hold the metadata of the frames in
+/// `tracked`.
+pub fn metadata_frame_count(tracked: &[Range<usize>]) -> usize {
+    let size: usize = tracked
+        .iter()
+        .map(|frames| metadata_size(frames.len()))
+        .sum();
+    (size + L0_FRAME_SIZE - 1) / L0_FRAME_SIZE
+}
+
+/// The number of bytes of metadata for `len` frames, padded so that the
+/// metadata for the next segment is aligned.
+fn metadata_size(len: usize) -> usize {
+    let size = len * (size_of::<AtomicU32>() + size_of::<AtomicU8>());
+    (size + align_of::<AtomicU32>() - 1) & !(align_of::<AtomicU32>() - 1)
+}
+
+/// Start tracking the frames in each of the disjoint ranges in `tracked`, all
+/// of which start out internal. Their metadata is placed in the frames
+/// starting at `metadata_start`, of which there must be as many as
+/// `metadata_frame_count` asks for.
+///
+/// # Safety
+/// Must be called exactly once, on boot, before any frame is used and after
+/// the frame mapping address is set. The metadata frames must be memory which
+/// nothing else uses, now or ever.
+pub unsafe fn init(tracked: &[Range<usize>], metadata_start: usize) {
+    assert!(tracked.len() <= MAX_SEGMENTS);
+    let frame_mapping_addr = FRAME_MAPPING_ADDR.load(Relaxed);
+    assert!(!frame_mapping_addr.is_null());
+    let mut addr = frame_mapping_addr.map_addr(|addr| addr + metadata_start * L0_FRAME_SIZE);
+    let size = metadata_frame_count(tracked) * L0_FRAME_SIZE;
+    // SAFETY: The caller ensures the metadata frames are ours alone. Zeroed
+    // metadata describes unreferenced, internal frames.
+    unsafe { addr.cast::<u8>().write_bytes(0x0, size) };
+
+    // SAFETY: Nothing else can be looking at the segments yet.
+    let segments = unsafe { SEGMENTS.borrow_mut() };
+    for (segment, frames) in segments.segments.iter_mut().zip(tracked) {
+        assert!(frames.end <= MAX_FRAME_COUNT);
+        let len = frames.len();
+        // SAFETY: The metadata is zeroed, suitably aligned, and lives forever.
+        let ref_counts = unsafe { slice::from_raw_parts(addr.cast::<AtomicU32>(), len) };
+        let frame_kinds = unsafe {
+            let addr = addr.map_addr(|addr| addr + len * size_of::<AtomicU32>());
+            slice::from_raw_parts(addr.cast::<AtomicU8>(), len)
+        };
+        *segment = Segment {
+            start: frames.start,
+            frame_kinds,
+            ref_counts,
+        };
+        addr = addr.map_addr(|addr| addr + metadata_size(len));
+    }
+    segments.len = tracked.len();
+}
+
+fn segment(frame_number: usize) -> Option<&'static Segment> {
+    // SAFETY: The segments are only modified by `init`, before any frame is
+    // used.
+    let segments = unsafe { SEGMENTS.borrow() };
+    segments.segments[..segments.len]
+        .iter()
+        .find(|segment| segment.frames().contains(&frame_number))
+}
+
+/// The metadata of the `len` frames starting at `frame_number`, provided they
+/// all lie within the same segment.
+fn metadata(
+    frame_number: usize,
+    len: usize,
+) -> Option<(&'static [AtomicU8], &'static [AtomicU32])> {
+    let segment = segment(frame_number)?;
+    let offset = frame_number - segment.start;
+    let frame_kinds = segment.frame_kinds.get(offset..offset + len)?;
+    let ref_counts = segment.ref_counts.get(offset..offset + len)?;
+    Some((frame_kinds, ref_counts))
+}
+
+fn frame_kind(idx: Idx) -> &'static AtomicU8 {
+    let (frame_kinds, _) = metadata(idx.into_raw(), 1).unwrap();
+    &frame_kinds[0]
+}
+
+/// # Safety
+/// Must only be called while booting, before the frame is referenced. The
+/// frame must be memory which only the kernel uses, through
+/// [`InternalArc::assume_init`].
+pub unsafe fn mark_internal(idx: Idx) {
+    frame_kind(idx).store(FrameKind::Internal as u8, Relaxed);
+}
+
+/// # Safety
+/// Must only be called while booting, before the frame is referenced. The
+/// frame must be memory which nothing else uses, since it may be handed out.
 pub unsafe fn mark_normal(idx: Idx) {
-    FRAME_KINDS[idx.into_raw()].store(FrameKind::Normal as u8, Relaxed);
+    frame_kind(idx).store(FrameKind::Normal as u8, Relaxed);
 }
 
+/// # Safety
+/// Must only be called while booting, before the frame is referenced. The
+/// frame must be device memory which nothing else uses, since it may be handed
+/// out.
 pub unsafe fn mark_device(idx: Idx) {
-    FRAME_KINDS[idx.into_raw()].store(FrameKind::External as u8, Relaxed);
+    frame_kind(idx).store(FrameKind::External as u8, Relaxed);
+}
+
+/// # Safety
+/// Must only be called while booting, before the frame is referenced. Once
+/// reserved, the frame is never handed out, so anything may use it.
+pub unsafe fn mark_reserved(idx: Idx) {
+    frame_kind(idx).store(FrameKind::Reserved as u8, Relaxed);
 }
 
 impl<T> AsRef<T> for Arc<T, NormalPolicy> {
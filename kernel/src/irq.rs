@@ -0,0 +1,232 @@
+//! Delivery of device interrupts to user mode.
+//!
+//! The kernel owns the PLIC, and hands each source, or line, to user mode
+//! through a handler capability, which the holder of the one IRQ control
+//! capability mints. Minting a line's first handler enables it on every hart.
+//!
+//! When a line interrupts, whichever hart takes the interrupt claims it from
+//! the PLIC and marks it pending. A thread holding one of the line's handlers
+//! then takes the pending interrupt, deals with the device, and acknowledges
+//! the interrupt, at which point the kernel completes the claim and the line
+//! may interrupt again. A thread which waits for an interrupt before one is
+//! pending is parked on the line, off the run queue, and takes the interrupt
+//! as soon as it arrives. Alternatively, a line may be bound to a notification,
+//! in which case its interrupts are taken on the handler's behalf and signal
+//! the notification instead.
+
+use crate::{
+    frame::Idx,
+    machine::MAX_HARTS,
+    notification::NotificationCap,
+    plic::{self, MAX_SOURCES},
+    sync::{hart_id, Token, TokenCell},
+    syscall::{Error, Result},
+    thread::ThreadCap,
+};
+
+/// The state of every line, which is shared by every hart.
+static LINES: TokenCell<Lines> = TokenCell::new(Lines {
+    states: [LineState::Idle; MAX_SOURCES],
+    notifications: [UNBOUND; MAX_SOURCES],
+    waiters: [NO_WAITER; MAX_SOURCES],
+    enabled: [0x0; MAX_SOURCES / 0x20],
+    harts: 0x0,
+});
+
+const UNBOUND: Option<(NotificationCap, usize)> = None;
+const NO_WAITER: Option<ThreadCap> = None;
+
+struct Lines {
+    states: [LineState; MAX_SOURCES],
+    /// The notification each line signals, along with the bits it signals.
+    notifications: [Option<(NotificationCap, usize)>; MAX_SOURCES],
+    /// The thread parked on each line until it interrupts, if any.
+    waiters: [Option<ThreadCap>; MAX_SOURCES],
+    /// Which lines are enabled, 32 to a word, as the PLIC lays them out.
+    enabled: [u32; MAX_SOURCES / 0x20],
+    /// The harts which take interrupts, and so need every enabled line enabled
+    /// for them too.
+    harts: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LineState {
+    /// The line may interrupt.
+    Idle,
+    /// The line has interrupted, and a handler has yet to take the interrupt.
+    /// The interrupt was claimed on `hart_id`'s context.
+    Pending { hart_id: u64 },
+    /// A handler has taken the interrupt, and has yet to acknowledge it. The
+    /// interrupt was claimed on `hart_id`'s context.
+    Taken { hart_id: u64 },
+}
+
+/// Start taking interrupts on the current hart.
+pub fn init_hart(token: &mut Token) {
+    let hart_id = hart_id();
+    let lines = LINES.borrow_mut(token);
+    lines.harts |= 0x1 << hart_id;
+    plic::set_threshold(hart_id, 0x0);
+    for (word, &enabled) in lines.enabled.iter().enumerate() {
+        plic::set_enabled(hart_id, word * 0x20, enabled);
+    }
+}
+
+/// Claim every interrupt pending for the current hart, handing each to the
+/// thread waiting on its line, or signalling the line's notification, or
+/// otherwise leaving it for its line's handlers.
+pub fn receive(token: &mut Token) {
+    let hart_id = hart_id();
+    while let Some(line) = plic::claim(hart_id) {
+        let lines = LINES.borrow_mut(token);
+        // Lines we haven't enabled can't interrupt, and a line can't interrupt
+        // again until its claim is completed.
+        debug_assert_eq!(lines.states[line], LineState::Idle);
+        if let Some(waiter) = lines.waiters[line].take() {
+            lines.states[line] = LineState::Taken { hart_id };
+            waiter.wake(token, Ok(0x0));
+        } else if let Some((notification, bits)) = lines.notifications[line].clone() {
+            lines.states[line] = LineState::Taken { hart_id };
+            notification.signal(token, bits);
+        } else {
+            lines.states[line] = LineState::Pending { hart_id };
+        }
+    }
+}
+
+/// The capability to mint handlers for any line.
+///
+/// There is only the one PLIC, so the capability refers to no frame.
+#[derive(Clone, Debug)]
+pub struct IrqControlCap(());
+
+impl IrqControlCap {
+    /// # Safety
+    /// Only those the kernel trusts with every device may control interrupts.
+    pub unsafe fn new() -> Self {
+        Self(())
+    }
+
+    /// Mint a handler for `line`, enabling the line if it isn't already.
+    pub fn handler(&self, token: &mut Token, line: usize) -> Option<IrqHandlerCap> {
+        if !plic::is_source(line) {
+            return None;
+        }
+        let lines = LINES.borrow_mut(token);
+        let (word, bit) = (line / 0x20, 0x1 << (line % 0x20));
+        if lines.enabled[word] & bit == 0x0 {
+            lines.enabled[word] |= bit;
+            plic::set_priority(line, 0x1);
+            for hart_id in 0x0..MAX_HARTS as u64 {
+                if lines.harts & (0x1 << hart_id) != 0x0 {
+                    plic::set_enabled(hart_id, word * 0x20, lines.enabled[word]);
+                }
+            }
+        }
+        Some(IrqHandlerCap { line })
+    }
+
+    pub fn into_frame_number(self) -> Idx {
+        Idx::DANGLING
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(_frame_number: Idx) -> Self {
+        Self(())
+    }
+}
+
+/// The capability to take and acknowledge the interrupts of a single line.
+///
+/// The capability refers to no frame, so it keeps its line in place of a frame
+/// number.
+#[derive(Clone, Debug)]
+pub struct IrqHandlerCap {
+    line: usize,
+}
+
+impl IrqHandlerCap {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Take the line's pending interrupt, returning whether there was one.
+    pub fn take(&self, token: &mut Token) -> bool {
+        let state = &mut LINES.borrow_mut(token).states[self.line];
+        if let LineState::Pending { hart_id } = *state {
+            *state = LineState::Taken { hart_id };
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take the line's pending interrupt on behalf of `thread`, or if there
+    /// is none, park `thread` on the line until it interrupts.
+    ///
+    /// Returns [`Error::Waiting`] if `thread` must wait, in which case it
+    /// will be woken once it has taken the interrupt. Only one thread may wait
+    /// on a line at a time, and none may wait on a line which is bound to a
+    /// notification, since its interrupts are taken on the handler's behalf.
+    pub fn wait(&self, token: &mut Token, thread: &ThreadCap) -> Result<()> {
+        if self.take(token) {
+            return Ok(());
+        }
+        let lines = LINES.borrow_mut(token);
+        if lines.notifications[self.line].is_some() || lines.waiters[self.line].is_some() {
+            return Err(Error::InvalidState);
+        }
+        lines.waiters[self.line] = Some(thread.clone());
+        Err(Error::Waiting)
+    }
+
+    /// Signal `bits` on `notification` whenever the line interrupts, or stop
+    /// signalling any notification if there is none.
+    ///
+    /// An interrupt which is already pending stays pending until it's taken.
+    /// Binding a notification turns away any thread waiting on the line.
+    pub fn bind(&self, token: &mut Token, notification: Option<NotificationCap>, bits: usize) {
+        let lines = LINES.borrow_mut(token);
+        let waiter = if notification.is_some() {
+            lines.waiters[self.line].take()
+        } else {
+            None
+        };
+        lines.notifications[self.line] = notification.map(|notification| (notification, bits));
+        if let Some(waiter) = waiter {
+            waiter.wake(token, Err(Error::InvalidState));
+        }
+    }
+
+    /// Acknowledge the interrupt which was taken from the line, so that the
+    /// line can interrupt again.
+    ///
+    /// The claim is completed on the context of the hart which claimed it,
+    /// whichever hart acknowledges it.
+    pub fn ack(&self, token: &mut Token) -> Option<()> {
+        let state = &mut LINES.borrow_mut(token).states[self.line];
+        if let LineState::Taken { hart_id } = *state {
+            *state = LineState::Idle;
+            plic::complete(hart_id, self.line);
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    pub fn into_frame_number(self) -> Idx {
+        // SAFETY: The line is only ever decoded by `from_frame_number`.
+        unsafe { Idx::from_raw_unchecked(self.line) }
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        Self {
+            line: frame_number.into_raw(),
+        }
+    }
+}
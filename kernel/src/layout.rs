@@ -11,6 +11,12 @@ extern "C" {
     #[link_name = "__boot_stack_pointer$"]
     pub static BOOT_STACK_POINTER: ();
 
+    #[link_name = "__hart_stacks_start$"]
+    pub static HART_STACKS_START: ();
+
+    #[link_name = "__hart_stacks_end$"]
+    pub static HART_STACKS_END: ();
+
     #[link_name = "__thread_data_start$"]
     pub static THREAD_DATA_START: ();
 
@@ -110,3 +116,22 @@ pub static KERNEL_LAYOUT: &'static [Section] = &[
         permissions: Permissions::ReadOnly,
     },
 ];
+
+/// The physical address the kernel image is loaded at.
+pub const IMAGE_PHYS_START: usize = 0x8020_0000;
+
+/// The number of bytes in the kernel image.
+pub fn image_size() -> usize {
+    let start = unsafe { addr_of!(ENTRY_START) }.addr();
+    let end = unsafe { addr_of!(CONST_END) }.addr();
+    end - start
+}
+
+/// The physical address of the kernel image byte at the virtual address
+/// `addr`, if it lies within the image.
+pub fn image_phys_addr(addr: usize) -> Option<usize> {
+    let start = unsafe { addr_of!(ENTRY_START) }.addr();
+    (start..start + image_size())
+        .contains(&addr)
+        .then(|| addr - start + IMAGE_PHYS_START)
+}
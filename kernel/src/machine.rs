@@ -1,7 +1,19 @@
-// We support up to 8GiB of physical address space.
-// TODO: Longer-term we should either fetch this dynamically from the device
-// tree, or have it be statically configurable for each target board.
-pub const FRAME_COUNT: usize = 0x20_0000;
 pub const L2_FRAME_SIZE: usize = 0x1000 * 512 * 512;
 pub const L1_FRAME_SIZE: usize = 0x1000 * 512;
 pub const L0_FRAME_SIZE: usize = 0x1000;
+
+// We can only reach the first 255GiB of physical address space through the
+// frame mapping, so frames above that are never tracked. Which frames below it
+// are tracked is up to the device tree.
+pub const MAX_FRAME_COUNT: usize = 0xff * (L2_FRAME_SIZE / L0_FRAME_SIZE);
+
+// Each hart needs its own kernel stack, and we only set aside stacks for this
+// many harts.
+// TODO: As with the physical address space, we should fetch this dynamically
+// from the device tree.
+pub const MAX_HARTS: usize = 8;
+
+// How long each thread runs before it's preempted, in ticks of the real-time
+// counter. This is 10ms at the 10MHz QEMU's virt machine counts at.
+// TODO: Fetch the counter's frequency from the device tree.
+pub const TIME_SLICE: u64 = 100_000;
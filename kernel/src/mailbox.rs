@@ -0,0 +1,64 @@
+//! Per-hart mailboxes, which let a hart ask other harts to do things.
+//!
+//! Sending a message sets its bit in the recipient's mailbox and raises a
+//! supervisor software interrupt on the recipient. The recipient notices the
+//! interrupt the next time it traps out of user mode or wakes from waiting for
+//! an interrupt, and then receives every message sent since it last looked.
+//! Sending the same message several times before it's received has the same
+//! effect as sending it once.
+
+use {
+    crate::{machine::MAX_HARTS, sbi::ipi, sync::hart_id},
+    ::core::{
+        ops::BitOr,
+        sync::atomic::{
+            AtomicU64,
+            Ordering::{Acquire, Release},
+        },
+    },
+};
+
+static MAILBOXES: [AtomicU64; MAX_HARTS] = {
+    const INIT: AtomicU64 = AtomicU64::new(0x0);
+    [INIT; MAX_HARTS]
+};
+
+/// Messages which can be sent to a hart.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Messages(u64);
+
+impl Messages {
+    pub const NONE: Self = Self(0x0);
+    /// Stop waiting for an interrupt, as there may be a thread to run.
+    pub const WAKE: Self = Self(0x1);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Messages {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Send `messages` to the hart `hart_id`, which must be below `MAX_HARTS`, as
+/// every hart which runs is.
+pub fn send(hart_id: u64, messages: Messages) {
+    // ORDERING: Anything we did before sending must be visible to the
+    // recipient once it receives.
+    MAILBOXES[hart_id as usize].fetch_or(messages.0, Release);
+    ipi::send_ipi(0x1 << hart_id, 0x0).expect("Sending an IPI should never fail.");
+}
+
+/// Receive every message sent to the current hart since it last received.
+pub fn receive() -> Messages {
+    // Acknowledge the interrupt before emptying the mailbox, so that anything
+    // sent after we look raises it again.
+    crate::plat::clear_software_interrupt();
+    // ORDERING: Anything the sender did before sending must be visible to us.
+    Messages(MAILBOXES[hart_id() as usize].swap(0x0, Acquire))
+}
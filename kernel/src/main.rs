@@ -18,12 +18,14 @@
 
 use static_assertions as _;
 
-use crate::{
-    frame::Idx,
-    layout::KERNEL_LAYOUT,
-    machine::{FRAME_COUNT, L0_FRAME_SIZE, L1_FRAME_SIZE},
-    sbi::srst::{reset_system, Reason, Type},
-    table::{set_kernel_l1_table, TABLE_LEN},
+use {
+    crate::{
+        frame::Idx,
+        layout::KERNEL_LAYOUT,
+        machine::{L0_FRAME_SIZE, L1_FRAME_SIZE, MAX_FRAME_COUNT, MAX_HARTS},
+        table::{asid_frame_count, init_asids, probe_asids, set_kernel_l1_table, TABLE_LEN},
+    },
+    ::core::ops::Range,
 };
 
 static_assertions::assert_cfg!(target_arch = "riscv64");
@@ -35,43 +37,126 @@ static_assertions::assert_cfg!(target_env = "sbi");
 pub mod debug;
 
 pub mod align;
+pub mod endpoint;
 pub mod entry;
+pub mod fdt;
 pub mod frame;
+pub mod irq;
 pub mod layout;
 pub mod machine;
+pub mod mailbox;
+pub mod notification;
 pub mod page;
 pub mod panic;
 pub mod plat;
+pub mod plic;
 pub mod ptr;
 pub mod sbi;
+pub mod sched;
 pub mod sync;
+pub mod syscall;
 pub mod table;
 pub mod thread;
+pub mod timer;
 
-pub fn main(frame_mapping_addr: *mut ()) -> ! {
+pub fn main(frame_mapping_addr: *mut (), fdt_addr: usize) -> ! {
     use crate::{
-        page::{InternalPageCap, NormalPageCap},
-        sbi::{base, legacy, srst},
+        debug::ConsoleCap,
+        fdt::{Fdt, RegionKind},
+        irq::IrqControlCap,
+        page::NormalPageCap,
+        sbi::{base, hsm, ipi, rfence, srst},
         sync::Token,
-        table::{L0TableCap, L1TableCap, L2TableCap},
+        table::{Cap, L0TableCap, L1TableCap, L2TableCap, Rights},
         thread::{Context, ThreadCap},
     };
 
     unsafe { frame::set_frame_mapping_addr(frame_mapping_addr) };
 
-    pub const DEVICE_START: usize = 0x0;
-    pub const DEVICE_END: usize = 0x8_0000;
-    pub const NORMAL_START: usize = 0xc_0000;
-    pub const NORMAL_END: usize = FRAME_COUNT;
+    //                                  0xffffffc000000000
+    const KERNELMODE_BASE_ADDR: usize = 0xffffffffc0000000;
+    const KERNELMODE_BASE_PHYS: usize = 0x0000000080000000;
 
-    for idx in DEVICE_START..DEVICE_END {
-        let idx = Idx::from_raw(idx).unwrap();
-        unsafe { frame::mark_device(idx) };
+    // SAFETY: The SBI hands us a valid device tree in memory, which nothing
+    // modifies.
+    let fdt = unsafe { Fdt::from_ptr(frame_mapping_addr.cast::<u8>().wrapping_add(fdt_addr)) }
+        .expect("The device tree should be valid.");
+
+    // Neither the firmware, the kernel image, the device tree itself, nor any
+    // other reserved memory may ever be handed out, even though they're
+    // memory. The firmware sits below the kernel image, which the kernel maps
+    // without referring to its frames.
+    let image_end = layout::IMAGE_PHYS_START + layout::image_size();
+    let mut reserved = FrameRanges::new();
+    reserved.insert(frames_overlapping(KERNELMODE_BASE_PHYS..image_end));
+    reserved.insert(frames_overlapping(fdt_addr..fdt_addr + fdt.size()));
+
+    // The kernel drives the PLIC itself, so user mode may never map it.
+    if let Some(plic) = fdt.plic() {
+        assert!(plic.addrs.end <= MAX_FRAME_COUNT * L0_FRAME_SIZE);
+        let regs = frame_mapping_addr
+            .cast::<u8>()
+            .wrapping_add(plic.addrs.start);
+        // SAFETY: The device tree says these are the PLIC's registers, and
+        // reserving them keeps them from anyone else.
+        unsafe { plic::init(regs.cast::<u32>(), plic.source_count) };
+        reserved.insert(frames_overlapping(plic.addrs));
+    } else {
+        kernel!("The device tree describes no PLIC, so there are no device interrupts.");
     }
 
-    for idx in NORMAL_START..NORMAL_END {
-        let idx = Idx::from_raw(idx).unwrap();
-        unsafe { frame::mark_normal(idx) };
+    // SAFETY: We're still running on the boot table.
+    unsafe { probe_asids() };
+
+    // We only track the frames the device tree tells us about, and keep their
+    // metadata, along with which harts use each ASID, in memory which nothing
+    // else is using.
+    let mut tracked = FrameRanges::new();
+    let mut memory = FrameRanges::new();
+    fdt.for_each_region(|region| match region.kind {
+        RegionKind::Memory => {
+            let frames = frames_within(region.addrs);
+            tracked.insert(frames.clone());
+            memory.insert(frames);
+        }
+        RegionKind::Device => tracked.insert(frames_overlapping(region.addrs)),
+        RegionKind::Reserved => reserved.insert(frames_overlapping(region.addrs)),
+    })
+    .expect("The device tree should be well formed.");
+    let metadata_len = frame::metadata_frame_count(tracked.as_slice());
+    let metadata_start = find_free_frames(memory.as_slice(), reserved.as_slice(), metadata_len)
+        .expect("There should be enough free memory for the frame metadata.");
+    reserved.insert(metadata_start..metadata_start + metadata_len);
+    let asids_len = asid_frame_count();
+    let asids_start = find_free_frames(memory.as_slice(), reserved.as_slice(), asids_len)
+        .expect("There should be enough free memory for the ASIDs.");
+    reserved.insert(asids_start..asids_start + asids_len);
+    // SAFETY: The metadata frames are memory which was free, and is now
+    // reserved, so nothing else ever uses them.
+    unsafe { frame::init(tracked.as_slice(), metadata_start) };
+    let asids_addr = frame_mapping_addr.map_addr(|addr| addr + asids_start * L0_FRAME_SIZE);
+    // SAFETY: Likewise for the ASID frames, and no L2 table exists yet.
+    unsafe { init_asids(asids_addr) };
+
+    fdt.for_each_region(|region| match region.kind {
+        RegionKind::Memory => {
+            for idx in frames_within(region.addrs) {
+                unsafe { frame::mark_normal(Idx::from_raw(idx).unwrap()) };
+            }
+        }
+        RegionKind::Device => {
+            for idx in frames_overlapping(region.addrs) {
+                unsafe { frame::mark_device(Idx::from_raw(idx).unwrap()) };
+            }
+        }
+        RegionKind::Reserved => {}
+    })
+    .expect("The device tree should be well formed.");
+
+    for frames in reserved.as_slice() {
+        for idx in frames.clone().filter_map(Idx::from_raw) {
+            unsafe { frame::mark_reserved(idx) };
+        }
     }
 
     let mut token = Token::acquire();
@@ -90,12 +175,19 @@ pub fn main(frame_mapping_addr: *mut ()) -> ! {
     let impl_ver = base::impl_version();
     kernel!("SBI implementation version: {:#x}", impl_ver);
 
-    let legacy_console_put = base::probe_extension(legacy::CONSOLE_PUT_EID);
-    assert!(matches!(legacy_console_put, base::ExtAvail::Available(_)));
+    debug::probe();
 
     let srst = base::probe_extension(srst::EID);
     assert!(matches!(srst, base::ExtAvail::Available(_)));
 
+    let ipi = base::probe_extension(ipi::EID);
+    assert!(matches!(ipi, base::ExtAvail::Available(_)));
+
+    // Other harts may only be started if we can fence their translations.
+    let rfence = base::probe_extension(rfence::EID);
+
+    unsafe { timer::probe() };
+
     let mvendor_id = base::machine_vendor_id();
     kernel!("SBI machine vendor ID: {}", mvendor_id);
 
@@ -105,14 +197,15 @@ pub fn main(frame_mapping_addr: *mut ()) -> ! {
     let mimpl_id = base::machine_impl_id();
     kernel!("SBI machine implementation ID: {:#x}", mimpl_id);
 
-    let mut boot_alloc = BootAlloc::new(NORMAL_START, NORMAL_END);
+    let memory_start = memory.as_slice().iter().map(|frames| frames.start).min();
+    let memory_end = memory.as_slice().iter().map(|frames| frames.end).max();
+    let (memory_start, memory_end) = memory_start
+        .zip(memory_end)
+        .expect("The device tree should describe memory.");
+    let mut boot_alloc = BootAlloc::new(memory_start, memory_end);
 
     kernel!("Boot allocator has {} frames of memory.", boot_alloc.len());
 
-    //                                  0xffffffc000000000
-    const KERNELMODE_BASE_ADDR: usize = 0xffffffffc0000000;
-    const KERNELMODE_BASE_PHYS: usize = 0x0000000080000000;
-
     let kernel_l1_table = boot_alloc.alloc(L1TableCap::new);
     for l1_index in 0..TABLE_LEN {
         let l0_table = boot_alloc.alloc(L0TableCap::new);
@@ -123,20 +216,18 @@ pub fn main(frame_mapping_addr: *mut ()) -> ! {
                     let phys_addr =
                         l0_index * L0_FRAME_SIZE + l1_index * L1_FRAME_SIZE + KERNELMODE_BASE_PHYS;
                     let idx = Idx::from_raw(phys_addr / L0_FRAME_SIZE).unwrap();
-                    let l0_page = unsafe { InternalPageCap::assume_init(idx) }.unwrap();
+                    // SAFETY: The frame is part of the kernel image, which is
+                    // reserved.
                     unsafe {
-                        l0_table.map_l0_kernel_page(
-                            &mut token,
-                            l0_index,
-                            l0_page,
-                            section.permissions,
-                        )
+                        l0_table.map_l0_kernel_page(&mut token, l0_index, idx, section.permissions)
                     };
                     break;
                 }
             }
         }
-        kernel_l1_table.map_l0_table(&mut token, l1_index, l0_table);
+        kernel_l1_table
+            .map_l0_table(&mut token, l1_index, l0_table)
+            .unwrap();
     }
 
     unsafe { set_kernel_l1_table(kernel_l1_table, &mut token) };
@@ -144,7 +235,8 @@ pub fn main(frame_mapping_addr: *mut ()) -> ! {
     const USERMODE_IMAGE: &'static [u8] = include_bytes!("../usermode_image");
     const USERMODE_BASE_ADDR: usize = 0x4000_0000usize;
 
-    let l2_table = boot_alloc.alloc(|idx| L2TableCap::new(idx, &token));
+    const ROOT_ASID: usize = 0x1;
+    let l2_table = boot_alloc.alloc(|idx| L2TableCap::new(idx, ROOT_ASID, &token));
     for (l2_index, l2_frame) in USERMODE_IMAGE
         .chunks(crate::machine::L2_FRAME_SIZE)
         .enumerate()
@@ -159,20 +251,54 @@ pub fn main(frame_mapping_addr: *mut ()) -> ! {
                 kernel!("Copying {} bytes into a l0 page.", l0_frame.len());
                 let l0_page = boot_alloc.alloc(|idx| NormalPageCap::new(idx, bytes));
                 kernel!("Mapping that l0 page at l0 index {}.", l0_index);
-                l0_table.map_l0_page(
-                    &mut token,
-                    l0_index,
-                    l0_page,
-                    table::Permissions::ReadWriteExecute,
-                );
+                l0_table
+                    .map_l0_page(
+                        &mut token,
+                        l0_index,
+                        l0_page,
+                        Rights::ALL,
+                        table::Permissions::ReadWriteExecute,
+                    )
+                    .unwrap();
             }
             kernel!("Mapping that l0 table at l1 index {}.", l1_index);
-            l1_table.map_l0_table(&mut token, l1_index, l0_table);
+            l1_table
+                .map_l0_table(&mut token, l1_index, l0_table)
+                .unwrap();
         }
         kernel!("Mapping that l1 table at l2 index {}.", l2_index);
-        l2_table.map_l1_table(&mut token, l2_index, l1_table);
+        l2_table
+            .map_l1_table(&mut token, l2_index, l1_table)
+            .unwrap();
     }
 
+    // The root thread's initial capabilities live in a single L0 table mapped
+    // at a fixed address. Each slot covers an L0 frame of the address space.
+    const USERMODE_CAP_ADDR: usize = 0x8000_0000usize;
+    const ROOT_L2_TABLE_SLOT: usize = 0x0;
+    const ROOT_L1_TABLE_SLOT: usize = 0x1;
+    const ROOT_L0_TABLE_SLOT: usize = 0x2;
+    const ROOT_THREAD_SLOT: usize = 0x3;
+    const ROOT_CONSOLE_SLOT: usize = 0x4;
+    const ROOT_IRQ_CONTROL_SLOT: usize = 0x5;
+
+    let cap_l1_table = boot_alloc.alloc(L1TableCap::new);
+    let cap_l0_table = boot_alloc.alloc(L0TableCap::new);
+    cap_l1_table
+        .map_l0_table(
+            &mut token,
+            (USERMODE_CAP_ADDR / crate::machine::L1_FRAME_SIZE) % TABLE_LEN,
+            cap_l0_table.clone(),
+        )
+        .unwrap();
+    l2_table
+        .map_l1_table(
+            &mut token,
+            USERMODE_CAP_ADDR / crate::machine::L2_FRAME_SIZE,
+            cap_l1_table.clone(),
+        )
+        .unwrap();
+
     kernel!("Boot allocator has {} frames of memory.", boot_alloc.len());
 
     let thread = boot_alloc.alloc(|frame_number| {
@@ -182,52 +308,59 @@ pub fn main(frame_mapping_addr: *mut ()) -> ! {
                 pc: USERMODE_BASE_ADDR,
                 ..Default::default()
             },
-            l2_table,
+            l2_table.clone(),
         )
     });
 
-    loop {
-        let scause;
-        let stval;
-        (token, scause, stval) = thread.resume(token).unwrap();
-
-        // TODO: define a new hart-local capability(s) that will allow a thread to
-        // block waiting on timer or device interrupts, switch to other threads, extend
-        // the timer, claim IRQs from the PLIC, and acknowledge those IRQs.
-
-        // TODO: define a system call interface
-
-        match scause {
-            0x8 => {
-                let context = thread.context_mut(&mut token).unwrap();
-                match context.a[0] {
-                    0x0 => {
-                        reset_system(Type::Shutdown, Reason::None).unwrap();
-                    }
-                    0x1 => {
-                        let bytes = context.a[1].to_be_bytes();
-                        if let Ok(str) = core::str::from_utf8(&bytes) {
-                            user!("{}", str.escape_debug());
-                        } else {
-                            user!("{:x?}", bytes);
-                        }
-                    }
-                    _ => {
-                        kernel!("Unexpected syscall attempt with context: {:?}", context);
-                    }
-                }
-                context.pc += 0x4;
+    for (slot, cap) in [
+        (ROOT_L2_TABLE_SLOT, Cap::L2Table(l2_table)),
+        (ROOT_L1_TABLE_SLOT, Cap::L1Table(cap_l1_table)),
+        (ROOT_L0_TABLE_SLOT, Cap::L0Table(cap_l0_table.clone())),
+        (ROOT_THREAD_SLOT, Cap::Thread(thread.clone())),
+        // SAFETY: The root thread is trusted with the console.
+        (
+            ROOT_CONSOLE_SLOT,
+            Cap::Console(unsafe { ConsoleCap::new() }),
+        ),
+        // SAFETY: The root thread is trusted with every device.
+        (
+            ROOT_IRQ_CONTROL_SLOT,
+            Cap::IrqControl(unsafe { IrqControlCap::new() }),
+        ),
+    ] {
+        cap_l0_table
+            .give_capability(&mut token, slot, cap, Rights::ALL)
+            .unwrap();
+    }
+
+    sched::push(&mut token, thread);
+
+    if !matches!(rfence, base::ExtAvail::Available(_)) {
+        kernel!("SBI RFENCE extension is unavailable, only using the boot hart.");
+    } else if let base::ExtAvail::Available(_) = base::probe_extension(hsm::EID) {
+        for hart_id in 0..MAX_HARTS as u64 {
+            if hart_id == sync::hart_id() {
+                continue;
             }
-            _ => {
-                panic!(
-                    "Unexpected user trap with context: {:?}, scause: {:#x}, stval: {:#x}",
-                    thread.context(&token),
-                    scause,
-                    stval,
-                );
+            if let Ok(hsm::Status::Stopped) = hsm::hart_get_status(hart_id) {
+                kernel!("Starting hart {}.", hart_id);
+                plat::start_hart(hart_id).unwrap();
             }
         }
+    } else {
+        kernel!("SBI HSM extension is unavailable, only using the boot hart.");
     }
+
+    sched::run(token)
+}
+
+/// The counterpart of [`main`] for every hart other than the boot hart.
+pub fn main_secondary() -> ! {
+    let token = sync::Token::acquire();
+
+    kernel!("Hart {} has started.", sync::hart_id());
+
+    sched::run(token)
 }
 
 impl BootAlloc {
@@ -243,16 +376,109 @@ impl BootAlloc {
         self.end_frame_number - self.start_frame_number
     }
 
-    pub fn alloc<T, F>(&mut self, f: F) -> T
+    /// Allocate the highest remaining frame which `f` accepts, skipping over
+    /// any frames, such as those which are reserved, that it doesn't.
+    pub fn alloc<T, F>(&mut self, mut f: F) -> T
     where
-        F: FnOnce(Idx) -> Option<T>,
+        F: FnMut(Idx) -> Option<T>,
     {
-        assert!(self.len() != 0);
-        let frame_number = self.end_frame_number - 1;
-        let idx = Idx::from_raw(frame_number).expect("Invalid frame number.");
-        let frame = f(idx).expect("Frame already in use.");
-        self.end_frame_number = frame_number;
-        frame
+        loop {
+            assert!(self.len() != 0, "Boot allocator is out of frames.");
+            let frame_number = self.end_frame_number - 1;
+            self.end_frame_number = frame_number;
+            // Skip the holes between ranges of memory, which we don't track.
+            if let Some(frame) = Idx::from_raw(frame_number).and_then(&mut f) {
+                return frame;
+            }
+        }
+    }
+}
+
+/// The frames which lie entirely within `addrs`, clamped to those we track.
+fn frames_within(addrs: Range<usize>) -> Range<usize> {
+    let start = (addrs.start + L0_FRAME_SIZE - 1) / L0_FRAME_SIZE;
+    let end = addrs.end / L0_FRAME_SIZE;
+    start.min(MAX_FRAME_COUNT)..end.min(MAX_FRAME_COUNT)
+}
+
+/// The frames which overlap `addrs` at all, clamped to those we track.
+fn frames_overlapping(addrs: Range<usize>) -> Range<usize> {
+    let start = addrs.start / L0_FRAME_SIZE;
+    let end = (addrs.end + L0_FRAME_SIZE - 1) / L0_FRAME_SIZE;
+    start.min(MAX_FRAME_COUNT)..end.min(MAX_FRAME_COUNT)
+}
+
+/// Find the highest `len` frames of `memory` which avoid every range of
+/// `reserved`, returning the first of them.
+fn find_free_frames(
+    memory: &[Range<usize>],
+    reserved: &[Range<usize>],
+    len: usize,
+) -> Option<usize> {
+    memory
+        .iter()
+        .filter_map(|frames| {
+            let mut end = frames.end;
+            loop {
+                let start = end
+                    .checked_sub(len)
+                    .filter(|&start| start >= frames.start)?;
+                let overlapping = reserved
+                    .iter()
+                    .filter(|reserved| reserved.start < end && start < reserved.end)
+                    .map(|reserved| reserved.start)
+                    .min();
+                match overlapping {
+                    Some(reserved_start) => end = reserved_start,
+                    None => return Some(start),
+                }
+            }
+        })
+        .max()
+}
+
+/// A set of disjoint ranges of frame numbers, gathered on boot before we can
+/// allocate anything.
+struct FrameRanges {
+    ranges: [Range<usize>; frame::MAX_SEGMENTS],
+    len: usize,
+}
+
+impl FrameRanges {
+    const fn new() -> Self {
+        const EMPTY: Range<usize> = 0x0..0x0;
+        Self {
+            ranges: [EMPTY; frame::MAX_SEGMENTS],
+            len: 0x0,
+        }
+    }
+
+    /// Add `frames`, merging it with any ranges it overlaps or abuts.
+    fn insert(&mut self, mut frames: Range<usize>) {
+        if frames.is_empty() {
+            return;
+        }
+        let mut index = 0x0;
+        while index < self.len {
+            let range = &self.ranges[index];
+            if range.start <= frames.end && frames.start <= range.end {
+                frames = range.start.min(frames.start)..range.end.max(frames.end);
+                self.len -= 0x1;
+                self.ranges.swap(index, self.len);
+            } else {
+                index += 0x1;
+            }
+        }
+        assert!(
+            self.len < self.ranges.len(),
+            "The device tree should describe fewer separate ranges of frames."
+        );
+        self.ranges[self.len] = frames;
+        self.len += 0x1;
+    }
+
+    fn as_slice(&self) -> &[Range<usize>] {
+        &self.ranges[..self.len]
     }
 }
 
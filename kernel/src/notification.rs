@@ -0,0 +1,243 @@
+//! Notifications, which let threads signal each other without blocking.
+//!
+//! A notification holds a word of signal bits. Signalling it ORs bits into the
+//! word, and waiting on it takes every bit which is set, blocking until at
+//! least one is. Device interrupts and timers can signal a notification too.
+//!
+//! Threads which wait while no bit is set queue on the notification, off the
+//! run queue, and each signal hands its bits straight to the thread at the
+//! front of the queue instead of setting them in the word.
+//!
+//! A badged notification signals the notification it was badged from, and
+//! always signals its badge rather than the bits it's given, so that whoever
+//! waits can tell its signallers apart.
+//!
+//! Each notification has a one-shot timer. The timers which are set form an
+//! intrusive list through the notifications themselves, which every hart
+//! checks whenever it schedules a thread.
+
+use crate::{
+    frame::{Idx, NormalArc},
+    sync::{Token, TokenCell},
+    syscall::{Error, Result},
+    thread::ThreadCap,
+};
+
+static TIMERS: TokenCell<Timers> = TokenCell::new(Timers {
+    head: None,
+    earliest: u64::MAX,
+});
+
+struct Timers {
+    head: Option<NotificationCap>,
+    /// No timer in the list fires before this deadline.
+    earliest: u64,
+}
+
+impl NotificationCap {
+    pub fn new(frame_number: Idx) -> Option<Self> {
+        let notification = Notification {
+            target: None,
+            badge: 0x0,
+            word: 0x0,
+            timer: None,
+            next_timer: None,
+            head: None,
+            tail: None,
+        };
+        let notification = TokenCell::new(notification);
+        let notification = NormalArc::new(frame_number, notification)?;
+        Some(Self { notification })
+    }
+
+    /// Create a notification which signals this one with `badge`.
+    ///
+    /// Only unbadged notifications can be badged, and the badge must be
+    /// nonzero.
+    pub fn badged(&self, token: &Token, frame_number: Idx, badge: usize) -> Result<Self> {
+        if self.badge(token) != 0x0 || badge == 0x0 {
+            return Err(Error::InvalidArgument);
+        }
+        let notification = Notification {
+            target: Some(self.clone()),
+            badge,
+            word: 0x0,
+            timer: None,
+            next_timer: None,
+            head: None,
+            tail: None,
+        };
+        let notification = TokenCell::new(notification);
+        let notification = NormalArc::new(frame_number, notification).ok_or(Error::InvalidFrame)?;
+        Ok(Self { notification })
+    }
+
+    pub fn badge(&self, token: &Token) -> usize {
+        self.notification.borrow(token).badge
+    }
+
+    /// OR `bits` into the notification's word, or the badge instead if the
+    /// notification is badged.
+    ///
+    /// If a thread is waiting, it's woken with the bits instead.
+    pub fn signal(&self, token: &mut Token, bits: usize) {
+        let notification = self.notification.borrow(token);
+        if let Some(target) = notification.target.clone() {
+            let badge = notification.badge;
+            target.signal(token, badge);
+        } else if let Some(waiter) = self.pop(token) {
+            waiter.wake(token, Ok(bits));
+        } else {
+            self.notification.borrow_mut(token).word |= bits;
+        }
+    }
+
+    /// Take every bit which is set in the notification's word, leaving it
+    /// clear.
+    pub fn take(&self, token: &mut Token) -> usize {
+        let notification = self.notification.borrow_mut(token);
+        if let Some(target) = notification.target.clone() {
+            return target.take(token);
+        }
+        ::core::mem::take(&mut notification.word)
+    }
+
+    /// Take every bit which is set in the notification's word on behalf of
+    /// `thread`, or if none is, queue `thread` until the notification is
+    /// signalled.
+    ///
+    /// Returns [`Error::Waiting`] if `thread` must wait, in which case it
+    /// will be woken with the bits it's signalled.
+    pub fn wait(&self, token: &mut Token, thread: &ThreadCap) -> Result<usize> {
+        let notification = self.notification.borrow(token);
+        if let Some(target) = notification.target.clone() {
+            return target.wait(token, thread);
+        }
+        match self.take(token) {
+            0x0 => {
+                self.push(token, thread.clone());
+                Err(Error::Waiting)
+            }
+            bits => Ok(bits),
+        }
+    }
+
+    /// Queue `thread` to wait for a signal.
+    fn push(&self, token: &mut Token, thread: ThreadCap) {
+        let notification = self.notification.borrow_mut(token);
+        let tail = notification.tail.replace(thread.clone());
+        if let Some(tail) = tail {
+            tail.set_next(token, thread);
+        } else {
+            self.notification.borrow_mut(token).head = Some(thread);
+        }
+    }
+
+    /// Take the thread at the front of the queue, if any.
+    fn pop(&self, token: &mut Token) -> Option<ThreadCap> {
+        let notification = self.notification.borrow_mut(token);
+        let head = notification.head.take()?;
+        let next = head.take_next(token);
+        let notification = self.notification.borrow_mut(token);
+        if next.is_none() {
+            notification.tail = None;
+        }
+        notification.head = next;
+        Some(head)
+    }
+
+    /// Signal `bits` once the real-time counter reaches `deadline`, replacing
+    /// the timer if it's already set.
+    pub fn set_timer(&self, token: &mut Token, deadline: u64, bits: usize) {
+        let notification = self.notification.borrow_mut(token);
+        let queued = notification.timer.is_some();
+        notification.timer = Some((deadline, bits));
+        let timers = TIMERS.borrow_mut(token);
+        timers.earliest = timers.earliest.min(deadline);
+        if !queued {
+            let head = timers.head.replace(self.clone());
+            self.notification.borrow_mut(token).next_timer = head;
+        }
+    }
+
+    /// Stop the timer from firing, if it's set.
+    ///
+    /// The notification is left in the list of timers until they're next
+    /// checked, along with the reference the list holds.
+    pub fn cancel_timer(&self, token: &mut Token) {
+        let notification = self.notification.borrow_mut(token);
+        if let Some((_, bits)) = notification.timer {
+            // A deadline which never arrives keeps the notification from
+            // being queued twice if its timer is set again.
+            notification.timer = Some((u64::MAX, bits));
+        }
+    }
+
+    pub fn into_frame_number(self) -> Idx {
+        self.notification.into_raw()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let notification = unsafe { NormalArc::from_raw(frame_number) };
+        Self { notification }
+    }
+}
+
+#[derive(Clone)]
+pub struct NotificationCap {
+    notification: NormalArc<TokenCell<Notification>>,
+}
+
+struct Notification {
+    /// The notification this one signals, if it's badged.
+    target: Option<NotificationCap>,
+    /// The bits signalled in place of those given, or zero if the notification
+    /// is unbadged.
+    badge: usize,
+    /// The signal bits, which are always clear if the notification is badged.
+    word: usize,
+    /// When the timer fires, in ticks of the real-time counter, and the bits
+    /// it signals. The notification is in the list of timers exactly when
+    /// this is set.
+    timer: Option<(u64, usize)>,
+    /// The notification after this one in the list of timers.
+    next_timer: Option<NotificationCap>,
+    /// The threads waiting for a signal, which are always empty if the
+    /// notification is badged or any bit is set.
+    head: Option<ThreadCap>,
+    tail: Option<ThreadCap>,
+}
+
+/// Fire every timer whose deadline has passed, returning the earliest deadline
+/// of those which remain.
+pub fn fire_timers(token: &mut Token, now: u64) -> Option<u64> {
+    let timers = TIMERS.borrow_mut(token);
+    if now < timers.earliest {
+        return Some(timers.earliest).filter(|&earliest| earliest != u64::MAX);
+    }
+    let mut next = timers.head.take();
+    let mut earliest = u64::MAX;
+    while let Some(notification) = next {
+        let state = notification.notification.borrow_mut(token);
+        next = state.next_timer.take();
+        match state.timer {
+            Some((deadline, bits)) if deadline <= now => {
+                state.timer = None;
+                notification.signal(token, bits);
+            }
+            Some((u64::MAX, _)) => state.timer = None,
+            Some((deadline, _)) => {
+                earliest = earliest.min(deadline);
+                let timers = TIMERS.borrow_mut(token);
+                let head = timers.head.replace(notification.clone());
+                notification.notification.borrow_mut(token).next_timer = head;
+            }
+            None => unreachable!("Only notifications with timers are in the list."),
+        }
+    }
+    TIMERS.borrow_mut(token).earliest = earliest;
+    Some(earliest).filter(|&earliest| earliest != u64::MAX)
+}
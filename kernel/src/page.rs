@@ -1,15 +1,28 @@
 use crate::frame::Idx;
-use crate::frame::{ExternalArc, InternalArc, NormalArc};
-use crate::machine::L0_FRAME_SIZE;
+use crate::frame::{ExternalArc, InternalArc, NormalArc, PartialSpan, SpanArc, SpanError};
+use crate::machine::{L0_FRAME_SIZE, L1_FRAME_SIZE, L2_FRAME_SIZE};
 
 pub struct InternalPageCap {
     page: InternalArc<()>,
 }
 
+#[derive(Clone)]
 pub struct NormalPageCap {
     page: NormalArc<[u8; L0_FRAME_SIZE]>,
 }
 
+/// A 2MiB page, mapped by a leaf entry of a L1 table.
+#[derive(Clone)]
+pub struct L1PageCap {
+    page: SpanArc<{ L1_FRAME_SIZE / L0_FRAME_SIZE }>,
+}
+
+/// A 1GiB page, mapped by a leaf entry of a L2 table.
+#[derive(Clone)]
+pub struct L2PageCap {
+    page: SpanArc<{ L2_FRAME_SIZE / L0_FRAME_SIZE }>,
+}
+
 pub struct ExternalPageCap {
     page: ExternalArc<()>,
 }
@@ -36,6 +49,62 @@ impl NormalPageCap {
         let Self { page } = self;
         page.into_raw()
     }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let page = unsafe { NormalArc::from_raw(frame_number) };
+        Self { page }
+    }
+}
+
+impl L1PageCap {
+    /// Create a zeroed page from the frames starting at `frame_number`, which
+    /// must be aligned to the page's size.
+    ///
+    /// See [`SpanArc::new_zeroed`] for how creation is continued.
+    pub fn new(frame_number: Idx, partial: Option<PartialSpan>) -> Result<Self, SpanError> {
+        let page = SpanArc::new_zeroed(frame_number, partial)?;
+        Ok(Self { page })
+    }
+
+    pub fn into_frame_number(self) -> Idx {
+        let Self { page } = self;
+        page.into_raw()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let page = unsafe { SpanArc::from_raw(frame_number) };
+        Self { page }
+    }
+}
+
+impl L2PageCap {
+    /// Create a zeroed page from the frames starting at `frame_number`, which
+    /// must be aligned to the page's size.
+    ///
+    /// See [`SpanArc::new_zeroed`] for how creation is continued.
+    pub fn new(frame_number: Idx, partial: Option<PartialSpan>) -> Result<Self, SpanError> {
+        let page = SpanArc::new_zeroed(frame_number, partial)?;
+        Ok(Self { page })
+    }
+
+    pub fn into_frame_number(self) -> Idx {
+        let Self { page } = self;
+        page.into_raw()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let page = unsafe { SpanArc::from_raw(frame_number) };
+        Self { page }
+    }
 }
 
 impl ExternalPageCap {
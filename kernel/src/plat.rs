@@ -1,10 +1,20 @@
-use ::core::{arch::asm, mem::size_of};
+use ::core::{arch::asm, mem::size_of, ptr::addr_of};
+
+pub fn satp() -> u64 {
+    let satp: u64;
+    unsafe {
+        asm!(
+            "csrr {satp}, satp",
+            satp = lateout(reg) satp,
+        )
+    }
+    satp
+}
 
 pub unsafe fn swap_satp(mut satp: u64) -> u64 {
     unsafe {
         asm!(
             "csrrw {satp}, satp, {satp}",
-            "sfence.vma zero, zero",
             satp = inout(reg) satp,
         )
     }
@@ -15,12 +25,150 @@ pub unsafe fn set_satp(satp: u64) {
     unsafe {
         asm!(
             "csrw satp, {satp}",
-            "sfence.vma zero, zero",
             satp = in(reg) satp,
         )
     }
 }
 
+/// Determine how many ASID bits the local hart implements, by writing ones to
+/// all of them and reading back which stuck.
+///
+/// # Safety
+/// The current address space must not depend on its ASID, as is the case for
+/// the boot table.
+pub unsafe fn probe_asid_bits() -> u32 {
+    const SATP_ASID_MASK: u64 = 0xffff << 44;
+    let probed: u64;
+    unsafe {
+        asm!(
+            "csrr {satp}, satp",
+            "or {probed}, {satp}, {asid_mask}",
+            "csrw satp, {probed}",
+            "csrr {probed}, satp",
+            "csrw satp, {satp}",
+            "sfence.vma zero, zero",
+            satp = out(reg) _,
+            probed = out(reg) probed,
+            asid_mask = in(reg) SATP_ASID_MASK,
+        )
+    }
+    (probed & SATP_ASID_MASK).count_ones()
+}
+
+/// Flush the local hart's address translation caches.
+pub fn sfence_vma() {
+    unsafe { asm!("sfence.vma zero, zero") }
+}
+
+/// Flush the local hart's non-global address translations for `asid`.
+pub fn sfence_vma_asid(asid: usize) {
+    unsafe { asm!("sfence.vma zero, {asid}", asid = in(reg) asid) }
+}
+
+/// Flush the local hart's non-global address translations for `addr` in
+/// `asid`.
+pub fn sfence_vma_addr_asid(addr: usize, asid: usize) {
+    unsafe { asm!("sfence.vma {addr}, {asid}", addr = in(reg) addr, asid = in(reg) asid) }
+}
+
+const SIE_SSIE_MASK: u64 = 0x2;
+const SIE_STIE_MASK: u64 = 0x20;
+const SIE_SEIE_MASK: u64 = 0x200;
+const SIP_SSIP_MASK: u64 = 0x2;
+
+/// Let supervisor software interrupts be taken on the local hart while it's in
+/// user mode, and wake it from [`wait_for_interrupt`].
+pub fn enable_software_interrupts() {
+    unsafe { asm!("csrs sie, {ssie}", ssie = in(reg) SIE_SSIE_MASK) }
+}
+
+/// Let supervisor timer interrupts be taken on the local hart while it's in
+/// user mode, and wake it from [`wait_for_interrupt`].
+pub fn enable_timer_interrupts() {
+    unsafe { asm!("csrs sie, {stie}", stie = in(reg) SIE_STIE_MASK) }
+}
+
+/// Let supervisor external interrupts be taken on the local hart while it's in
+/// user mode, and wake it from [`wait_for_interrupt`].
+pub fn enable_external_interrupts() {
+    unsafe { asm!("csrs sie, {seie}", seie = in(reg) SIE_SEIE_MASK) }
+}
+
+/// Acknowledge a supervisor software interrupt on the local hart.
+pub fn clear_software_interrupt() {
+    unsafe { asm!("csrc sip, {ssip}", ssip = in(reg) SIP_SSIP_MASK) }
+}
+
+/// Stall the local hart until an enabled interrupt may be pending.
+///
+/// This may return spuriously.
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi") }
+}
+
+/// The current value of the real-time counter.
+pub fn time() -> u64 {
+    let time: u64;
+    unsafe {
+        asm!(
+            "csrr {time}, time",
+            time = lateout(reg) time,
+        )
+    }
+    time
+}
+
+/// Determine whether the local hart lets us access `stimecmp`, as it does when
+/// it implements the Sstc extension and the SBI has enabled it for us.
+///
+/// # Safety
+/// No traps may be taken from supervisor mode while probing, as we temporarily
+/// redirect them.
+pub unsafe fn probe_sstc() -> bool {
+    let sstc: u64;
+    unsafe {
+        asm!(
+            // If reading `stimecmp` traps, we skip setting the flag.
+            "csrr {stvec}, stvec",
+            "la {sstc}, 1f",
+            "csrw stvec, {sstc}",
+            "li {sstc}, 0x0",
+            "csrr {stimecmp}, 0x14d",
+            "li {sstc}, 0x1",
+
+            // Note that trap handlers must be aligned on 4-byte boundaries.
+            ".align 0x4",
+            "1:",
+            "csrw stvec, {stvec}",
+
+            // Had we trapped, we would have come from supervisor mode, so set
+            // the previous privilege back to usermode.
+            "li {stimecmp}, {sstatus_spp_mask}",
+            "csrc sstatus, {stimecmp}",
+
+            stvec = out(reg) _,
+            stimecmp = out(reg) _,
+            sstc = out(reg) sstc,
+            sstatus_spp_mask = const SSTATUS_SPP_MASK,
+        )
+    }
+    sstc != 0x0
+}
+
+/// Raise a supervisor timer interrupt on the local hart once the real-time
+/// counter reaches `deadline`.
+///
+/// # Safety
+/// The local hart must implement the Sstc extension.
+pub unsafe fn set_stimecmp(deadline: u64) {
+    unsafe {
+        asm!(
+            "csrw 0x14d, {deadline}",
+            deadline = in(reg) deadline,
+        )
+    }
+}
+
 pub unsafe fn resume(context: &mut crate::thread::Context) -> (u64, u64) {
     let sstatus: u64;
     unsafe {
@@ -208,15 +356,61 @@ pub unsafe fn call(
 use crate::{
     align::L0FrameAligned,
     layout::{
-        BOOT_STACK_POINTER, BOOT_THREAD_POINTER, ENTRY_START, GLOBAL_POINTER, THREAD_BSS_END,
-        THREAD_BSS_START, THREAD_DATA_END, THREAD_DATA_START,
+        image_phys_addr, BOOT_STACK_POINTER, BOOT_THREAD_POINTER, ENTRY_START, GLOBAL_POINTER,
+        HART_STACKS_END, HART_STACKS_START, THREAD_BSS_END, THREAD_BSS_START, THREAD_DATA_END,
+        THREAD_DATA_START, THREAD_IMAGE_END, THREAD_IMAGE_START,
     },
-    main,
+    machine::{L0_FRAME_SIZE, MAX_HARTS},
+    main, main_secondary,
+    sbi::{hsm, StandardError},
     sync::set_hart_id,
     table::{boot_l2_table, L2Entry, TABLE_LEN},
     thread::SSTATUS_SPP_MASK,
 };
 
+/// A L2 page table with nothing except the kernel (high half) mapped. This is
+/// only used while a hart boots, before it bootstraps its first context.
+static BOOT_L2_TABLE: L0FrameAligned<[L2Entry; TABLE_LEN]> = L0FrameAligned(boot_l2_table());
+
+/// The size of the kernel stack of each hart other than the boot hart. The
+/// hart's thread-local storage lives at the bottom of its stack.
+const HART_STACK_SIZE: usize = 0x10 * L0_FRAME_SIZE;
+
+/// Start the stopped hart `hart_id`, which will enter the kernel through
+/// [`boot_secondary`] on its own stack.
+pub fn start_hart(hart_id: u64) -> Result<(), StandardError> {
+    let stacks_start = unsafe { addr_of!(HART_STACKS_START) }.addr();
+    let stacks_end = unsafe { addr_of!(HART_STACKS_END) }.addr();
+    assert_eq!(stacks_end - stacks_start, MAX_HARTS * HART_STACK_SIZE);
+    let thread_image_start = unsafe { addr_of!(THREAD_IMAGE_START) }.addr();
+    let thread_image_end = unsafe { addr_of!(THREAD_IMAGE_END) }.addr();
+    assert!(thread_image_end - thread_image_start < HART_STACK_SIZE);
+
+    let hart_index = usize::try_from(hart_id).map_err(|_| StandardError::InvalidParam)?;
+    if hart_index >= MAX_HARTS {
+        return Err(StandardError::InvalidParam);
+    }
+    let stack_pointer = stacks_start + (hart_index + 1) * HART_STACK_SIZE;
+
+    // The new hart starts with ATP disabled, so it needs the physical address
+    // of its entrypoint.
+    let start_addr = image_phys_addr((boot_secondary as *const ()).addr()).unwrap();
+
+    // SAFETY: `boot_secondary` is the entrypoint for a fresh hart, and expects
+    // the top of a stack no other hart uses.
+    unsafe { hsm::hart_start(hart_id, start_addr, stack_pointer) }
+}
+
+/// Refuse to run a hart whose ID is out of range, since per-hart state only has
+/// room for `MAX_HARTS` harts.
+fn check_hart_id(hart_id: u64) {
+    assert!(
+        hart_id < MAX_HARTS as u64,
+        "Only harts with IDs below {} are supported.",
+        MAX_HARTS
+    );
+}
+
 /// Enters execution of the kernel in supervisor mode on boot.
 ///
 /// # Safety
@@ -225,17 +419,14 @@ use crate::{
 #[export_name = "__entry$"]
 #[link_section = ".entry"]
 pub unsafe extern "C" fn boot(_hart_id: u64, _fdt: u64) -> ! {
-    unsafe extern "C" fn handle_boot(hart_id: u64, _fdt: u64, frame_mapping_addr: *mut ()) -> ! {
+    unsafe extern "C" fn handle_boot(hart_id: u64, fdt: u64, frame_mapping_addr: *mut ()) -> ! {
         // SAFETY: SBI ensures that the hart ID is unique and accurate.
         unsafe { set_hart_id(hart_id) };
+        check_hart_id(hart_id);
 
-        main(frame_mapping_addr)
+        main(frame_mapping_addr, fdt as usize)
     }
 
-    /// A L2 page table with nothing except the kernel (high half) mapped. This
-    /// is only used during boot before we bootstrap the initial context.
-    static BOOT_L2_TABLE: L0FrameAligned<[L2Entry; TABLE_LEN]> = L0FrameAligned(boot_l2_table());
-
     // SAFETY: We entered via the SBI's boot sequence. See below for the
     // reasoning behind each block of instructions.
     unsafe {
@@ -396,6 +587,133 @@ pub unsafe extern "C" fn boot(_hart_id: u64, _fdt: u64) -> ! {
     }
 }
 
+/// Enters execution of the kernel in supervisor mode on a hart started by
+/// [`start_hart`].
+///
+/// This mirrors [`boot`], except that each hart brings its own stack and
+/// thread-local storage.
+///
+/// # Safety
+/// Must be called by the SBI exactly once on each hart other than the boot
+/// hart, with the top of a stack reserved for that hart as `stack_pointer`.
+#[naked]
+#[link_section = ".entry"]
+pub unsafe extern "C" fn boot_secondary(_hart_id: u64, _stack_pointer: u64) -> ! {
+    unsafe extern "C" fn handle_boot_secondary(hart_id: u64) -> ! {
+        // SAFETY: SBI ensures that the hart ID is unique and accurate.
+        unsafe { set_hart_id(hart_id) };
+        check_hart_id(hart_id);
+
+        main_secondary()
+    }
+
+    // SAFETY: We entered via the SBI's hart start sequence, which makes the
+    // same guarantees as the boot sequence, except that the second argument
+    // register holds the opaque value we passed along.
+    unsafe {
+        asm!(
+            ".option push",
+            ".option norelax",
+
+            // Get into the kernel's virtual address space the same way the
+            // boot hart does.
+            "li t0, {virt_start}",
+            "la t1, {phys_start}",
+            "sub t1, t0, t1",
+            "la t0, 1f",
+            "add t0, t0, t1",
+            "csrw stvec, t0",
+
+            "la t0, {boot_l2_table}",
+            "srli t0, t0, 12",
+            "li t1, {satp_mode_sv39}",
+            "or t0, t1, t0",
+            "sfence.vma zero, zero",
+            "csrw satp, t0",
+
+            "j .",
+
+            ".align 0x4",
+
+            "1:",
+
+            // Setup the rest of the supervisor state.
+            "li t0, {sstatus_spp_mask}",
+            "csrc sstatus, t0",
+
+            "la t0, {stvec_base}",
+            "csrw stvec, t0",
+
+            "csrw sie, zero",
+            "csrw sip, zero",
+
+            "li t0, {sstatus_fs_mask}",
+            "csrc sstatus, t0",
+
+            "la gp, {global_pointer}",
+
+            ".option pop",
+
+            // Our stack was handed to us, and our TLS block sits at the bottom
+            // of it.
+            "mv sp, a1",
+            "li t0, {hart_stack_size}",
+            "sub tp, sp, t0",
+
+            // Copy the TLS data and zero the TLS BSS.
+            "la t0, {thread_data_start}",
+            "la t1, {thread_data_end}",
+            "mv t2, tp",
+            "2:",
+            "beq t0, t1, 3f",
+            "lb t3, 0(t0)",
+            "sb t3, 0(t2)",
+            "addi t0, t0, 1",
+            "addi t2, t2, 1",
+            "j 2b",
+            "3:",
+            "la t0, {thread_bss_start}",
+            "la t1, {thread_bss_end}",
+            "4:",
+            "beq t0, t1, 5f",
+            "sb zero, 0(t2)",
+            "addi t0, t0, 1",
+            "addi t2, t2, 1",
+            "j 4b",
+            "5:",
+
+            // The hart ID is still in the first argument register.
+            "call {handle_boot_secondary}",
+
+            phys_start = sym ENTRY_START,
+            virt_start = const 0xffff_ffff_c020_0000u64,
+
+            boot_l2_table = sym BOOT_L2_TABLE,
+            satp_mode_sv39 = const 0x8000_0000_0000_0000u64,
+
+            sstatus_spp_mask = const SSTATUS_SPP_MASK,
+
+            stvec_base = sym supervisor_trap,
+
+            sstatus_fs_mask = const 0x6000u64,
+
+            global_pointer = sym GLOBAL_POINTER,
+
+            hart_stack_size = const HART_STACK_SIZE,
+
+            thread_data_start = sym THREAD_DATA_START,
+            thread_data_end = sym THREAD_DATA_END,
+
+            thread_bss_start = sym THREAD_BSS_START,
+            thread_bss_end = sym THREAD_BSS_END,
+
+            handle_boot_secondary = sym handle_boot_secondary,
+
+            options(noreturn)
+        )
+    }
+}
+
 /// Enters execution of the kernel upon a trap from supervisor mode.
 ///
 /// # Safety
@@ -0,0 +1,127 @@
+//! A driver for the platform-level interrupt controller, which routes the
+//! interrupts of devices to harts.
+//!
+//! Each hart has a context for each privilege mode it can take interrupts in,
+//! and a context only takes an interrupt from a source which is enabled for it
+//! and whose priority exceeds its threshold. Taking an interrupt means claiming
+//! it, after which the source won't interrupt again until the claim is
+//! completed.
+//!
+//! We only ever use the supervisor contexts, and assume they're numbered as
+//! they are on QEMU's virt machine, rather than following each context's
+//! `interrupts-extended` entry in the device tree back to its hart.
+//!
+//! See the [PLIC specification][0] for the register layout.
+//!
+//! [0]: https://github.com/riscv/riscv-plic-spec
+
+use ::core::{
+    ptr::{self, null_mut},
+    sync::atomic::{
+        AtomicPtr, AtomicUsize,
+        Ordering::{Acquire, Release},
+    },
+};
+
+const PRIORITY_OFFSET: usize = 0x0;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD_OFFSET: usize = 0x0;
+const CLAIM_OFFSET: usize = 0x4;
+
+/// The most sources a PLIC can have, including the nonexistent source zero.
+pub const MAX_SOURCES: usize = 0x400;
+
+/// The base of the registers, which is null if there's no PLIC.
+static REGS: AtomicPtr<u32> = AtomicPtr::new(null_mut());
+/// The number of sources, including the nonexistent source zero.
+static SOURCE_COUNT: AtomicUsize = AtomicUsize::new(0x0);
+
+/// Start driving the PLIC whose registers are at `regs`, which has
+/// `source_count` sources besides the nonexistent source zero.
+///
+/// # Safety
+/// `regs` must be the registers of a PLIC, which nothing else may touch.
+pub unsafe fn init(regs: *mut u32, source_count: usize) {
+    SOURCE_COUNT.store((source_count + 0x1).min(MAX_SOURCES), Release);
+    REGS.store(regs, Release);
+}
+
+/// The number of sources, including the nonexistent source zero, or zero if
+/// there's no PLIC.
+pub fn source_count() -> usize {
+    if REGS.load(Acquire).is_null() {
+        return 0x0;
+    }
+    SOURCE_COUNT.load(Acquire)
+}
+
+/// Whether `source` is a source which exists.
+pub fn is_source(source: usize) -> bool {
+    (0x1..source_count()).contains(&source)
+}
+
+pub fn set_priority(source: usize, priority: u32) {
+    debug_assert!(is_source(source));
+    write(PRIORITY_OFFSET + source * 0x4, priority);
+}
+
+/// Set whether each of the 32 sources starting at `first_source` are enabled
+/// for `hart_id`.
+pub fn set_enabled(hart_id: u64, first_source: usize, enabled: u32) {
+    debug_assert!(first_source % 0x20 == 0x0 && first_source < MAX_SOURCES);
+    let offset = ENABLE_OFFSET + context(hart_id) * ENABLE_STRIDE + first_source / 0x8;
+    write(offset, enabled);
+}
+
+/// Interrupt `hart_id` for any enabled source with a priority above
+/// `threshold`.
+pub fn set_threshold(hart_id: u64, threshold: u32) {
+    write(context_offset(hart_id) + THRESHOLD_OFFSET, threshold);
+}
+
+/// Claim the highest priority source which is pending for `hart_id`, if any.
+pub fn claim(hart_id: u64) -> Option<usize> {
+    let source = read(context_offset(hart_id) + CLAIM_OFFSET)?;
+    (source != 0x0).then(|| source as usize)
+}
+
+/// Complete a claim of `source`, so that it can interrupt again.
+///
+/// The source must still be enabled for `hart_id`, or the completion is
+/// ignored.
+pub fn complete(hart_id: u64, source: usize) {
+    debug_assert!(is_source(source));
+    write(context_offset(hart_id) + CLAIM_OFFSET, source as u32);
+}
+
+/// The supervisor context of `hart_id`.
+fn context(hart_id: u64) -> usize {
+    0x2 * hart_id as usize + 0x1
+}
+
+fn context_offset(hart_id: u64) -> usize {
+    CONTEXT_OFFSET + context(hart_id) * CONTEXT_STRIDE
+}
+
+fn read(offset: usize) -> Option<u32> {
+    let regs = REGS.load(Acquire);
+    if regs.is_null() {
+        return None;
+    }
+    // SAFETY: The PLIC's registers are ours alone, and every offset we use is
+    // a register.
+    Some(unsafe { ptr::read_volatile(regs.map_addr(|addr| addr + offset)) })
+}
+
+fn write(offset: usize, value: u32) {
+    let regs = REGS.load(Acquire);
+    if regs.is_null() {
+        return;
+    }
+    // SAFETY: The PLIC's registers are ours alone, and every offset we use is
+    // a register.
+    unsafe { ptr::write_volatile(regs.map_addr(|addr| addr + offset), value) }
+}
@@ -0,0 +1,33 @@
+use crate::sbi::call;
+
+pub const EID: u32 = 0x4442434E;
+
+/// Write up to `len` bytes starting at the physical address `addr` to the
+/// debug console, returning how many were written.
+///
+/// # Safety
+/// The `len` bytes starting at `addr` must be readable.
+pub unsafe fn console_write(len: usize, addr: usize) -> Result<usize, super::StandardError> {
+    // Safety: The caller ensures the SBI may read the bytes.
+    unsafe { call(EID, 0x0, len, addr, 0, 0, 0, 0) }
+}
+
+/// Read up to `len` bytes from the debug console into the physical address
+/// `addr`, returning how many were read. Doesn't block if there's nothing to
+/// read.
+///
+/// # Safety
+/// The `len` bytes starting at `addr` must be writable, and nothing may depend
+/// on their contents.
+pub unsafe fn console_read(len: usize, addr: usize) -> Result<usize, super::StandardError> {
+    // Safety: The caller ensures the SBI may write the bytes.
+    unsafe { call(EID, 0x1, len, addr, 0, 0, 0, 0) }
+}
+
+/// Write a single byte to the debug console, blocking until it's written.
+pub fn console_write_byte(b: u8) -> Result<(), super::StandardError> {
+    // Safety: It is always legal to write to the debug console via SBI in
+    // supervisor mode.
+    let res = unsafe { call(EID, 0x2, b as usize, 0, 0, 0, 0, 0) };
+    res.map(|_| ())
+}
@@ -0,0 +1,57 @@
+use crate::sbi::call;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Status {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(usize),
+}
+
+pub const EID: u32 = 0x48534D;
+
+/// Start executing the stopped hart `hart_id` in supervisor mode at the
+/// physical address `start_addr`, with address translation disabled, its hart
+/// ID in `a0` and `opaque` in `a1`.
+///
+/// # Safety
+/// `start_addr` must be a suitable entrypoint for a fresh hart, and `opaque`
+/// must be what that entrypoint expects.
+pub unsafe fn hart_start(
+    hart_id: u64,
+    start_addr: usize,
+    opaque: usize,
+) -> Result<(), super::StandardError> {
+    // Safety: The caller ensures the hart starts somewhere sensible.
+    let res = unsafe { call(EID, 0x0, hart_id as usize, start_addr, opaque, 0, 0, 0) };
+    res.map(|_| ())
+}
+
+/// Stop executing on the current hart, returning it to the SBI.
+///
+/// # Safety
+/// Nothing may depend on the current hart making further progress.
+pub unsafe fn hart_stop() -> Result<(), super::StandardError> {
+    // Safety: The caller ensures it's fine for this hart to stop.
+    let res = unsafe { call(EID, 0x1, 0, 0, 0, 0, 0, 0) };
+    res.map(|_| unreachable!())
+}
+
+pub fn hart_get_status(hart_id: u64) -> Result<Status, super::StandardError> {
+    // Safety: Querying the status of a hart has no side effects.
+    let res = unsafe { call(EID, 0x2, hart_id as usize, 0, 0, 0, 0, 0) };
+    res.map(|status| match status {
+        0 => Status::Started,
+        1 => Status::Stopped,
+        2 => Status::StartPending,
+        3 => Status::StopPending,
+        4 => Status::Suspended,
+        5 => Status::SuspendPending,
+        6 => Status::ResumePending,
+        status => Status::Unknown(status),
+    })
+}
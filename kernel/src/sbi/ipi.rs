@@ -0,0 +1,12 @@
+use crate::sbi::call;
+
+pub const EID: u32 = 0x735049;
+
+/// Raise a supervisor software interrupt on the harts in `hart_mask`, whose
+/// bits are offset by `hart_mask_base`.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), super::StandardError> {
+    // Safety: Software interrupts are only delivered to the kernel, which
+    // decides for itself what to do with them.
+    let res = unsafe { call(EID, 0x0, hart_mask, hart_mask_base, 0, 0, 0, 0) };
+    res.map(|_| ())
+}
@@ -1,6 +1,7 @@
 use crate::sbi::call;
 
 pub const CONSOLE_PUT_EID: u32 = 0x1;
+pub const CONSOLE_GET_EID: u32 = 0x2;
 
 pub fn console_put(b: u8) {
     // Safety: It is always legal to put a character to the debug console via
@@ -8,3 +9,12 @@ pub fn console_put(b: u8) {
     let r = unsafe { call(CONSOLE_PUT_EID, 0x0, b as usize, 0, 0, 0, 0, 0) };
     drop(r)
 }
+
+pub fn console_get() -> Option<u8> {
+    // Safety: It is always legal to get a character from the debug console via
+    // SBI in supervisor mode.
+    // Note that the legacy extension returns the character in place of the
+    // error, or -1 if there is none.
+    let (c, _) = unsafe { crate::plat::call(CONSOLE_GET_EID, 0x0, 0, 0, 0, 0, 0, 0) };
+    u8::try_from(c as isize).ok()
+}
@@ -1,6 +1,11 @@
 pub mod base;
+pub mod dbcn;
+pub mod hsm;
+pub mod ipi;
 pub mod legacy;
+pub mod rfence;
 pub mod srst;
+pub mod time;
 
 /// A standard error returned from an SBI call.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
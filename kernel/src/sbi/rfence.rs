@@ -0,0 +1,51 @@
+use crate::sbi::call;
+
+pub const EID: u32 = 0x52464E43;
+
+/// Instruct the harts in `hart_mask`, whose bits are offset by
+/// `hart_mask_base`, to execute `fence.i`.
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> Result<(), super::StandardError> {
+    // Safety: Remote fences only affect the performance of the remote harts.
+    let res = unsafe { call(EID, 0x0, hart_mask, hart_mask_base, 0, 0, 0, 0) };
+    res.map(|_| ())
+}
+
+/// Instruct the harts in `hart_mask`, whose bits are offset by
+/// `hart_mask_base`, to execute `sfence.vma` for the `size` bytes of virtual
+/// addresses starting at `start_addr` in every address space.
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+) -> Result<(), super::StandardError> {
+    // Safety: Remote fences only affect the performance of the remote harts.
+    let res = unsafe { call(EID, 0x1, hart_mask, hart_mask_base, start_addr, size, 0, 0) };
+    res.map(|_| ())
+}
+
+/// Instruct the harts in `hart_mask`, whose bits are offset by
+/// `hart_mask_base`, to execute `sfence.vma` for the `size` bytes of virtual
+/// addresses starting at `start_addr` in the address space `asid`.
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+    asid: usize,
+) -> Result<(), super::StandardError> {
+    // Safety: Remote fences only affect the performance of the remote harts.
+    let res = unsafe {
+        call(
+            EID,
+            0x2,
+            hart_mask,
+            hart_mask_base,
+            start_addr,
+            size,
+            asid,
+            0,
+        )
+    };
+    res.map(|_| ())
+}
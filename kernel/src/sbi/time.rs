@@ -0,0 +1,12 @@
+use crate::sbi::call;
+
+pub const EID: u32 = 0x54494D45;
+
+/// Raise a supervisor timer interrupt on the current hart once the real-time
+/// counter reaches `stime_value`, clearing any that is pending.
+pub fn set_timer(stime_value: u64) -> Result<(), super::StandardError> {
+    // Safety: Timer interrupts are only delivered to the kernel, which decides
+    // for itself what to do with them.
+    let res = unsafe { call(EID, 0x0, stime_value as usize, 0, 0, 0, 0, 0) };
+    res.map(|_| ())
+}
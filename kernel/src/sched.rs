@@ -0,0 +1,174 @@
+//! Scheduling of threads onto harts.
+//!
+//! Every hart runs the same loop: take the thread at the front of a single,
+//! shared run queue, run it until it traps, handle the trap, and put the thread
+//! back at the end of the queue. The queue is an intrusive list threaded
+//! through the threads themselves, so it never needs to allocate.
+//!
+//! Threads are preempted once they have run for a time slice, so every queued
+//! thread gets a turn even if the one running never traps on its own.
+//!
+//! A hart which finds the run queue empty waits for an interrupt, and whoever
+//! next queues a thread sends it a wakeup through its mailbox.
+//!
+//! A thread which faults enters its exception call rather than taking the
+//! kernel down with it, and if it can't, it's simply never queued again.
+
+use {
+    crate::{
+        irq,
+        machine::TIME_SLICE,
+        mailbox::{self, Messages},
+        notification, plat,
+        sync::{hart_id, Token, TokenCell},
+        syscall,
+        table::{self, Access},
+        thread::ThreadCap,
+        timer,
+    },
+    ::core::sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+const SUPERVISOR_SOFTWARE_INTERRUPT: u64 = 0x8000_0000_0000_0001;
+const SUPERVISOR_TIMER_INTERRUPT: u64 = 0x8000_0000_0000_0005;
+const SUPERVISOR_EXTERNAL_INTERRUPT: u64 = 0x8000_0000_0000_0009;
+
+static RUN_QUEUE: TokenCell<RunQueue> = TokenCell::new(RunQueue {
+    head: None,
+    tail: None,
+});
+
+/// The harts waiting for a thread to be queued.
+static IDLE_HARTS: AtomicU64 = AtomicU64::new(0x0);
+
+struct RunQueue {
+    head: Option<ThreadCap>,
+    tail: Option<ThreadCap>,
+}
+
+/// Add `thread` to the end of the run queue.
+///
+/// The thread must not already be queued or running.
+pub fn push(token: &mut Token, thread: ThreadCap) {
+    let tail = RUN_QUEUE.borrow_mut(token).tail.replace(thread.clone());
+    if let Some(tail) = tail {
+        tail.set_next(token, thread);
+    } else {
+        RUN_QUEUE.borrow_mut(token).head = Some(thread);
+    }
+
+    let idle_harts = IDLE_HARTS.load(Relaxed);
+    if idle_harts != 0x0 {
+        let idle_hart = u64::from(idle_harts.trailing_zeros());
+        IDLE_HARTS.fetch_and(!(0x1u64 << idle_hart), Relaxed);
+        mailbox::send(idle_hart, Messages::WAKE);
+    }
+}
+
+/// Take the thread at the front of the run queue.
+pub fn pop(token: &mut Token) -> Option<ThreadCap> {
+    let head = RUN_QUEUE.borrow_mut(token).head.take()?;
+    let next = head.take_next(token);
+    let run_queue = RUN_QUEUE.borrow_mut(token);
+    if next.is_none() {
+        run_queue.tail = None;
+    }
+    run_queue.head = next;
+    Some(head)
+}
+
+/// Run threads from the run queue on the current hart, forever.
+pub fn run(mut token: Token) -> ! {
+    plat::enable_software_interrupts();
+    plat::enable_timer_interrupts();
+    plat::enable_external_interrupts();
+    irq::init_hart(&mut token);
+
+    loop {
+        let next_timer = notification::fire_timers(&mut token, plat::time());
+        // Whatever died while handling the last trap must be destroyed before
+        // we give up the token.
+        table::destroy_dead_tables(&mut token);
+
+        let thread = if let Some(thread) = pop(&mut token) {
+            thread
+        } else {
+            // Nothing to run, so wait to be woken. We mark ourselves idle
+            // before letting anyone else at the run queue, so that whoever
+            // queues a thread next is sure to see us, and their wakeup stays
+            // pending until we wait for it.
+            // A stale timer interrupt would keep waking us, so disarm it, unless
+            // there's a notification's timer to fire.
+            if let Some(next_timer) = next_timer {
+                timer::set_deadline(next_timer);
+            } else {
+                timer::clear_deadline();
+            }
+            IDLE_HARTS.fetch_or(0x1 << hart_id(), Relaxed);
+            token.release();
+            plat::wait_for_interrupt();
+            mailbox::receive();
+            token = Token::acquire();
+            IDLE_HARTS.fetch_and(!(0x1 << hart_id()), Relaxed);
+            irq::receive(&mut token);
+            continue;
+        };
+
+        let deadline = plat::time() + TIME_SLICE;
+        timer::set_deadline(next_timer.map_or(deadline, |next_timer| next_timer.min(deadline)));
+
+        let scause;
+        let stval;
+        (token, scause, stval) = thread.resume(token).unwrap();
+
+        // TODO: define a new hart-local capability(s) that will allow a thread to
+        // switch to other threads and extend its time slice.
+
+        match scause {
+            // Whatever the messages, the thread trapped out so it goes back
+            // on the run queue below, which is all preemption asks for.
+            SUPERVISOR_SOFTWARE_INTERRUPT => {
+                mailbox::receive();
+            }
+            // The thread's time slice is up, or a notification's timer is due,
+            // so the thread goes to the back of the run queue below. Its next
+            // time slice replaces the deadline.
+            SUPERVISOR_TIMER_INTERRUPT => {}
+            SUPERVISOR_EXTERNAL_INTERRUPT => irq::receive(&mut token),
+            0x8 => {
+                // A thread which now waits on another is put back on the run
+                // queue by whoever it's waiting for.
+                if !syscall::handle(&mut token, &thread) {
+                    continue;
+                }
+            }
+            0xc | 0xd | 0xf
+                if thread
+                    .l2_table(&token)
+                    .clone()
+                    .update_accessed_dirty(
+                        &mut token,
+                        stval as usize,
+                        Access::from_page_fault(scause),
+                    )
+                    .is_some() => {}
+            // Anything else is the thread's own fault, for its exception call
+            // to deal with. Failing that, nothing else can, so the thread
+            // never runs again.
+            _ => {
+                if let Err(error) = thread.call_exception(&mut token, scause, stval) {
+                    kernel!(
+                        "Suspending thread after unhandled user trap ({:?}) with context: {:?}, scause: {:#x}, stval: {:#x}",
+                        error,
+                        thread.context(&token),
+                        scause,
+                        stval,
+                    );
+                    continue;
+                }
+            }
+        }
+
+        push(&mut token, thread);
+    }
+}
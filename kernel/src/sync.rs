@@ -145,6 +145,11 @@ static TOKEN_HOLDER: AtomicU64 = AtomicU64::new(INVALID_HART_ID);
 #[thread_local]
 static HART_ID: Cell<u64> = Cell::new(INVALID_HART_ID);
 
+/// The ID of the current hart.
+pub fn hart_id() -> u64 {
+    HART_ID.get()
+}
+
 // SAFETY: The caller must ensure that the hart ID given is accurate and unique
 // to the caller's current hart, and must not be equal to `u64::MAX`.
 pub unsafe fn set_hart_id(hart_id: u64) {
@@ -0,0 +1,948 @@
+//! The system call interface.
+//!
+//! User mode invokes a capability with `ecall`, passing the address of the
+//! capability's slot in `a0`, an operation label in `a1`, and up to six
+//! arguments in `a2` through `a7`. The kernel resolves the capability through
+//! the invoking thread's tables, dispatches on its type and the label, and
+//! returns an error code in `a0` (zero on success) and a value in `a1`. All
+//! other registers are preserved.
+//!
+//! Each slot also confers a set of rights over its capability. Operations which
+//! modify the invoked object require the control right, and copying a
+//! capability requires the grant right on its source, so that a thread can be
+//! handed, say, a page it may only map read-only or a call it may only call.
+//!
+//! Calling a call capability switches the thread into the callee's address
+//! space, and delivers its badge to the callee in `a1`, which is zero unless
+//! the call was badged. The arguments in `a2` through `a7` pass through to the
+//! callee untouched, while every other register is cleared, and those the
+//! caller expects to be preserved are restored once the callee returns.
+//! Returning passes `a2` through `a7` back to the caller the same way.
+//! Likewise, anyone holding a notification may signal it, but only with control
+//! may they wait on it.
+//!
+//! Some operations may block. Rather than returning, a blocked invocation is
+//! retried from the top each time its thread is scheduled, until it completes.
+//! The exceptions are IPC through endpoints, waiting on a notification, and
+//! waiting for an interrupt, where a thread waits off the run queue until
+//! whoever it's waiting for delivers its result.
+//!
+//! Sending on an endpoint passes `a2` through `a7` as the message. The
+//! receiver gets the endpoint's badge in `a1` and the message in `a2` through
+//! `a7`, and a caller gets its reply the same way.
+//!
+//! The null capability pointer never resolves to a capability. Instead,
+//! invoking it provides a small set of debugging operations, along with
+//! returning from a call, which needs no capability. None of them reach beyond
+//! the invoking thread, so shutting down the machine is left to the console
+//! capability instead.
+
+use crate::{
+    debug::ConsoleCap,
+    endpoint::{self, EndpointCap},
+    frame::{Idx, PartialSpan, SpanError},
+    irq::{IrqControlCap, IrqHandlerCap},
+    notification::NotificationCap,
+    page::{L1PageCap, L2PageCap, NormalPageCap},
+    plat,
+    sbi::srst::{reset_system, Reason, Type},
+    sync::Token,
+    table::{
+        is_asid_free, Cap, L0TableCap, L1TableCap, L2TableCap, LookupError, Permissions, Rights,
+        SlotError, TABLE_LEN, USER_L2_INDICES,
+    },
+    thread::{CallCap, ThreadCap},
+};
+
+/// The capability pointer which designates the debugging operations.
+pub const NULL: usize = 0x0;
+
+/// Operation labels, namespaced by the type of the invoked capability.
+pub mod label {
+    pub const NULL_PUT: usize = 0x1;
+    pub const NULL_RETURN: usize = 0x2;
+
+    pub const L2_TABLE_MAP_L1_TABLE: usize = 0x0;
+    pub const L2_TABLE_UNMAP_L1_TABLE: usize = 0x1;
+    pub const L2_TABLE_MAP_L2_PAGE: usize = 0x2;
+    pub const L2_TABLE_UNMAP_L2_PAGE: usize = 0x3;
+
+    pub const L1_TABLE_MAP_L0_TABLE: usize = 0x0;
+    pub const L1_TABLE_UNMAP_L0_TABLE: usize = 0x1;
+    pub const L1_TABLE_MAP_L1_PAGE: usize = 0x2;
+    pub const L1_TABLE_UNMAP_L1_PAGE: usize = 0x3;
+
+    pub const L0_TABLE_MAP_L0_PAGE: usize = 0x0;
+    pub const L0_TABLE_NEW_L2_TABLE: usize = 0x1;
+    pub const L0_TABLE_NEW_L1_TABLE: usize = 0x2;
+    pub const L0_TABLE_NEW_L0_TABLE: usize = 0x3;
+    pub const L0_TABLE_NEW_L0_PAGE: usize = 0x4;
+    pub const L0_TABLE_NEW_CALL: usize = 0x5;
+    pub const L0_TABLE_COPY: usize = 0x6;
+    pub const L0_TABLE_MOVE: usize = 0x7;
+    pub const L0_TABLE_DELETE: usize = 0x8;
+    pub const L0_TABLE_DERIVE: usize = 0x9;
+    pub const L0_TABLE_REVOKE: usize = 0xa;
+    pub const L0_TABLE_MINT: usize = 0xb;
+    pub const L0_TABLE_BADGE: usize = 0xc;
+    pub const L0_TABLE_UNMAP_L0_PAGE: usize = 0xd;
+    pub const L0_TABLE_NEW_L2_PAGE: usize = 0xe;
+    pub const L0_TABLE_NEW_L1_PAGE: usize = 0xf;
+    pub const L0_TABLE_TAKE_ACCESSED_DIRTY: usize = 0x10;
+    pub const L0_TABLE_NEW_NOTIFICATION: usize = 0x11;
+    pub const L0_TABLE_NEW_ENDPOINT: usize = 0x12;
+
+    pub const THREAD_SET_EXCEPTION_CALL: usize = 0x0;
+
+    pub const CALL_CALL: usize = 0x0;
+
+    pub const CONSOLE_POLL: usize = 0x0;
+    pub const CONSOLE_SHUTDOWN: usize = 0x1;
+
+    pub const IRQ_CONTROL_GET_HANDLER: usize = 0x0;
+
+    pub const IRQ_HANDLER_WAIT: usize = 0x0;
+    pub const IRQ_HANDLER_POLL: usize = 0x1;
+    pub const IRQ_HANDLER_ACK: usize = 0x2;
+    pub const IRQ_HANDLER_SET_NOTIFICATION: usize = 0x3;
+
+    pub const NOTIFICATION_SIGNAL: usize = 0x0;
+    pub const NOTIFICATION_WAIT: usize = 0x1;
+    pub const NOTIFICATION_POLL: usize = 0x2;
+    pub const NOTIFICATION_SET_TIMER: usize = 0x3;
+
+    pub const ENDPOINT_SEND: usize = 0x0;
+    pub const ENDPOINT_CALL: usize = 0x1;
+    pub const ENDPOINT_RECV: usize = 0x2;
+    pub const ENDPOINT_REPLY: usize = 0x3;
+    pub const ENDPOINT_REPLY_RECV: usize = 0x4;
+}
+
+/// An error returned to user mode in `a0`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[repr(usize)]
+pub enum Error {
+    /// The capability pointer resolved to a capability of an unexpected type,
+    /// or to a mapped page.
+    InvalidCapability = 0x1,
+    /// The label is not an operation of the invoked capability.
+    InvalidLabel = 0x2,
+    /// An argument was out of range or malformed.
+    InvalidArgument = 0x3,
+    /// The frame is not a free frame of normal memory.
+    InvalidFrame = 0x4,
+    /// The destination slot or entry is already in use.
+    Occupied = 0x5,
+    /// The operation could not be completed in the thread's current state.
+    InvalidState = 0x6,
+    /// The capability pointer lies outside of the user mappable range.
+    InvalidAddress = 0x7,
+    /// No table is mapped along the path to the capability pointer's slot.
+    MissingTable = 0x8,
+    /// The capability pointer's slot is empty.
+    EmptySlot = 0x9,
+    /// The capability is derived, so it can only be copied by deriving it
+    /// again, and can't be mapped or held by another object.
+    Derived = 0xa,
+    /// The capability is not derived, so it cannot be revoked.
+    NotDerived = 0xb,
+    /// The capability has derived children, which must be revoked first.
+    HasChildren = 0xc,
+    /// The capability does not confer the rights the operation requires.
+    InsufficientRights = 0xd,
+    /// Nothing is mapped at the table entry.
+    NotMapped = 0xe,
+    /// The ASID is reserved, out of range, or already in use.
+    InvalidAsid = 0xf,
+    /// The operation would have had to block, but was asked not to.
+    WouldBlock = 0x10,
+    /// The thread is already as many calls deep as it can be.
+    CallStackFull = 0x11,
+    /// The thread's context was restored as a whole, so there is no result to
+    /// deliver. This is never returned to user mode.
+    Restored = 0xfffd,
+    /// The thread must wait for another to deliver its result. This is never
+    /// returned to user mode.
+    Waiting = 0xfffe,
+    /// The operation must block. This is never returned to user mode, which
+    /// instead retries the operation.
+    Blocked = 0xffff,
+}
+
+/// The number of descendants a single revoke invocation may delete.
+const REVOKE_BUDGET: usize = 0x10;
+
+impl From<LookupError> for Error {
+    fn from(error: LookupError) -> Self {
+        match error {
+            LookupError::InvalidAddress => Self::InvalidAddress,
+            LookupError::MissingL1Table | LookupError::MissingL0Table => Self::MissingTable,
+            LookupError::MappedPage => Self::InvalidCapability,
+            LookupError::EmptySlot => Self::EmptySlot,
+        }
+    }
+}
+
+impl From<SlotError> for Error {
+    fn from(error: SlotError) -> Self {
+        match error {
+            SlotError::Empty => Self::EmptySlot,
+            SlotError::Occupied => Self::Occupied,
+            SlotError::Derived => Self::Derived,
+            SlotError::NotDerived => Self::NotDerived,
+            SlotError::HasChildren => Self::HasChildren,
+            SlotError::InvalidFrame => Self::InvalidFrame,
+            SlotError::InsufficientRights => Self::InsufficientRights,
+        }
+    }
+}
+
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// Handle an environment call from `thread`, returning whether it may keep
+/// running rather than waiting on another thread.
+///
+/// The thread must have just trapped, so that its context is available.
+pub fn handle(token: &mut Token, thread: &ThreadCap) -> bool {
+    let context = thread
+        .context_mut(token)
+        .expect("A trapped thread should have a context.");
+    // Skip over the `ecall` so that we don't simply trap again.
+    context.pc += 0x4;
+    let [cap_ptr, label, args @ ..] = context.a;
+
+    let result = invoke(token, thread, cap_ptr, label, args);
+    // A thread only keeps the span it's building a page from for as long as it
+    // retries building the page.
+    if !matches!(result, Err(Error::Blocked)) {
+        drop(thread.take_partial_span(token));
+    }
+    let (error, value) = match result {
+        Ok(value) => (0x0, value),
+        Err(Error::Blocked) => {
+            // Leave the thread at its `ecall` with its arguments intact, so
+            // that it tries again when it's next scheduled.
+            let context = thread
+                .context_mut(token)
+                .expect("A trapped thread should have a context.");
+            context.pc -= 0x4;
+            return true;
+        }
+        // Returning from an exception call restores every register, which
+        // the result mustn't overwrite.
+        Err(Error::Restored) => return true,
+        Err(Error::Waiting) => return false,
+        Err(error) => (error as usize, 0x0),
+    };
+
+    // Note that the invocation may have changed the thread's context.
+    let context = thread
+        .context_mut(token)
+        .expect("A trapped thread should have a context.");
+    context.a[0] = error;
+    context.a[1] = value;
+    true
+}
+
+fn invoke(
+    token: &mut Token,
+    thread: &ThreadCap,
+    cap_ptr: usize,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    if cap_ptr == NULL {
+        return invoke_null(token, thread, label, args);
+    }
+    let (cap, rights) = lookup(token, thread, cap_ptr)?;
+    // Calls, notifications and endpoints are the only capabilities which may
+    // be invoked without control, and the latter two only to signal or send.
+    let uncontrolled = matches!(cap, Cap::Call(_) | Cap::Notification(_) | Cap::Endpoint(_));
+    if !uncontrolled && !rights.contains(Rights::CONTROL) {
+        return Err(Error::InsufficientRights);
+    }
+    match cap {
+        Cap::L2Table(l2_table) => invoke_l2_table(token, thread, &l2_table, label, args),
+        Cap::L1Table(l1_table) => invoke_l1_table(token, thread, &l1_table, label, args),
+        Cap::L0Table(l0_table) => invoke_l0_table(token, thread, &l0_table, label, args),
+        Cap::Thread(target) => invoke_thread(token, thread, &target, label, args),
+        Cap::Call(call) => invoke_call(token, thread, &call, label, args),
+        Cap::Console(console) => invoke_console(&console, label, args),
+        Cap::IrqControl(irq_control) => {
+            invoke_irq_control(token, thread, &irq_control, label, args)
+        }
+        Cap::IrqHandler(irq_handler) => {
+            invoke_irq_handler(token, thread, &irq_handler, label, args)
+        }
+        Cap::Notification(notification) => {
+            invoke_notification(token, thread, &notification, rights, label, args)
+        }
+        Cap::Endpoint(endpoint) => invoke_endpoint(token, thread, &endpoint, rights, label),
+        Cap::L2Page(_) | Cap::L1Page(_) | Cap::L0Page(_) => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_null(
+    token: &mut Token,
+    thread: &ThreadCap,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::NULL_PUT => {
+            let bytes = args[0].to_be_bytes();
+            if let Ok(str) = ::core::str::from_utf8(&bytes) {
+                user!("{}", str.escape_debug());
+            } else {
+                user!("{:x?}", bytes);
+            }
+            Ok(0x0)
+        }
+        label::NULL_RETURN => {
+            if thread.ret(token)? {
+                return Err(Error::Restored);
+            }
+            Ok(0x0)
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_l2_table(
+    token: &mut Token,
+    thread: &ThreadCap,
+    l2_table: &L2TableCap,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::L2_TABLE_MAP_L1_TABLE => {
+            let [index, l1_table, ..] = args;
+            if !USER_L2_INDICES.contains(&index) {
+                return Err(Error::InvalidArgument);
+            }
+            check_underived(token, thread, l1_table)?;
+            let l1_table = lookup_l1_table(token, thread, l1_table)?;
+            l2_table
+                .map_l1_table(token, index, l1_table)
+                .ok_or(Error::Occupied)?;
+            Ok(0x0)
+        }
+        label::L2_TABLE_UNMAP_L1_TABLE => {
+            let [index, dst, dst_index, ..] = args;
+            if !USER_L2_INDICES.contains(&index) {
+                return Err(Error::InvalidArgument);
+            }
+            let dst_index = table_index(dst_index)?;
+            let dst = lookup_vacant(token, thread, dst, dst_index)?;
+            let l1_table = l2_table
+                .unmap_l1_table(token, index)
+                .ok_or(Error::NotMapped)?;
+            let cap = Cap::L1Table(l1_table);
+            give_unmapped(token, &dst, dst_index, cap, Rights::CONTROL)
+        }
+        label::L2_TABLE_MAP_L2_PAGE => {
+            let [index, l2_page, permissions, ..] = args;
+            if !USER_L2_INDICES.contains(&index) {
+                return Err(Error::InvalidArgument);
+            }
+            check_underived(token, thread, l2_page)?;
+            let (l2_page, rights) = lookup_l2_page(token, thread, l2_page)?;
+            let permissions =
+                Permissions::try_from(permissions).map_err(|()| Error::InvalidArgument)?;
+            l2_table.map_l2_page(token, index, l2_page, rights, permissions)?;
+            Ok(0x0)
+        }
+        label::L2_TABLE_UNMAP_L2_PAGE => {
+            let [index, dst, dst_index, ..] = args;
+            if !USER_L2_INDICES.contains(&index) {
+                return Err(Error::InvalidArgument);
+            }
+            let dst_index = table_index(dst_index)?;
+            let dst = lookup_vacant(token, thread, dst, dst_index)?;
+            let (l2_page, permissions) = l2_table
+                .unmap_l2_page(token, index)
+                .ok_or(Error::NotMapped)?;
+            let cap = Cap::L2Page(l2_page);
+            give_unmapped(token, &dst, dst_index, cap, permissions.rights())
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_l1_table(
+    token: &mut Token,
+    thread: &ThreadCap,
+    l1_table: &L1TableCap,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::L1_TABLE_MAP_L0_TABLE => {
+            let [index, l0_table, ..] = args;
+            let index = table_index(index)?;
+            check_underived(token, thread, l0_table)?;
+            let l0_table = lookup_l0_table(token, thread, l0_table)?;
+            l1_table
+                .map_l0_table(token, index, l0_table)
+                .ok_or(Error::Occupied)?;
+            Ok(0x0)
+        }
+        label::L1_TABLE_UNMAP_L0_TABLE => {
+            let [index, dst, dst_index, ..] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let dst = lookup_vacant(token, thread, dst, dst_index)?;
+            let l0_table = l1_table
+                .unmap_l0_table(token, index)
+                .ok_or(Error::NotMapped)?;
+            let cap = Cap::L0Table(l0_table);
+            give_unmapped(token, &dst, dst_index, cap, Rights::CONTROL)
+        }
+        label::L1_TABLE_MAP_L1_PAGE => {
+            let [index, l1_page, permissions, ..] = args;
+            let index = table_index(index)?;
+            check_underived(token, thread, l1_page)?;
+            let (l1_page, rights) = lookup_l1_page(token, thread, l1_page)?;
+            let permissions =
+                Permissions::try_from(permissions).map_err(|()| Error::InvalidArgument)?;
+            l1_table.map_l1_page(token, index, l1_page, rights, permissions)?;
+            Ok(0x0)
+        }
+        label::L1_TABLE_UNMAP_L1_PAGE => {
+            let [index, dst, dst_index, ..] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let dst = lookup_vacant(token, thread, dst, dst_index)?;
+            let (l1_page, permissions) = l1_table
+                .unmap_l1_page(token, index)
+                .ok_or(Error::NotMapped)?;
+            let cap = Cap::L1Page(l1_page);
+            give_unmapped(token, &dst, dst_index, cap, permissions.rights())
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_l0_table(
+    token: &mut Token,
+    thread: &ThreadCap,
+    l0_table: &L0TableCap,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::L0_TABLE_MAP_L0_PAGE => {
+            let [index, l0_page, permissions, ..] = args;
+            let index = table_index(index)?;
+            check_underived(token, thread, l0_page)?;
+            let (l0_page, rights) = lookup_l0_page(token, thread, l0_page)?;
+            let permissions =
+                Permissions::try_from(permissions).map_err(|()| Error::InvalidArgument)?;
+            l0_table.map_l0_page(token, index, l0_page, rights, permissions)?;
+            Ok(0x0)
+        }
+        label::L0_TABLE_UNMAP_L0_PAGE => {
+            let [index, dst, dst_index, ..] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let dst = lookup_vacant(token, thread, dst, dst_index)?;
+            let (l0_page, permissions) = l0_table
+                .unmap_l0_page(token, index)
+                .ok_or(Error::NotMapped)?;
+            let cap = Cap::L0Page(l0_page);
+            give_unmapped(token, &dst, dst_index, cap, permissions.rights())
+        }
+        label::L0_TABLE_TAKE_ACCESSED_DIRTY => {
+            let [start, len, ..] = args;
+            let end = start.checked_add(len).ok_or(Error::InvalidArgument)?;
+            if end > TABLE_LEN || len > usize::BITS as usize / 2 {
+                return Err(Error::InvalidArgument);
+            }
+            Ok(l0_table.take_accessed_dirty(token, start..end))
+        }
+        label::L0_TABLE_NEW_L2_TABLE => {
+            let [index, frame_number, asid, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            if !is_asid_free(asid) {
+                return Err(Error::InvalidAsid);
+            }
+            let l2_table = L2TableCap::new(frame_number, asid, token).ok_or(Error::InvalidFrame)?;
+            give(token, l0_table, index, Cap::L2Table(l2_table))
+        }
+        label::L0_TABLE_NEW_L1_TABLE => {
+            let [index, frame_number, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            let l1_table = L1TableCap::new(frame_number).ok_or(Error::InvalidFrame)?;
+            give(token, l0_table, index, Cap::L1Table(l1_table))
+        }
+        label::L0_TABLE_NEW_L0_TABLE => {
+            let [index, frame_number, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            let new_l0_table = L0TableCap::new(frame_number).ok_or(Error::InvalidFrame)?;
+            give(token, l0_table, index, Cap::L0Table(new_l0_table))
+        }
+        label::L0_TABLE_NEW_L0_PAGE => {
+            let [index, frame_number, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            let bytes = [0x0; crate::machine::L0_FRAME_SIZE];
+            let l0_page = NormalPageCap::new(frame_number, bytes).ok_or(Error::InvalidFrame)?;
+            give(token, l0_table, index, Cap::L0Page(l0_page))
+        }
+        label::L0_TABLE_NEW_L2_PAGE => {
+            let [index, frame_number, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            // Creating the page may take many attempts, so fail early.
+            if !l0_table.is_empty(token, index) {
+                return Err(Error::Occupied);
+            }
+            let l2_page = build_page(token, thread, |partial| {
+                L2PageCap::new(frame_number, partial)
+            })?;
+            give(token, l0_table, index, Cap::L2Page(l2_page))
+        }
+        label::L0_TABLE_NEW_L1_PAGE => {
+            let [index, frame_number, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            let l1_page = build_page(token, thread, |partial| {
+                L1PageCap::new(frame_number, partial)
+            })?;
+            give(token, l0_table, index, Cap::L1Page(l1_page))
+        }
+        label::L0_TABLE_NEW_NOTIFICATION => {
+            let [index, frame_number, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            let notification = NotificationCap::new(frame_number).ok_or(Error::InvalidFrame)?;
+            give(token, l0_table, index, Cap::Notification(notification))
+        }
+        label::L0_TABLE_NEW_ENDPOINT => {
+            let [index, frame_number, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            let endpoint = EndpointCap::new(frame_number).ok_or(Error::InvalidFrame)?;
+            give(token, l0_table, index, Cap::Endpoint(endpoint))
+        }
+        label::L0_TABLE_NEW_CALL => {
+            let [index, frame_number, l2_table, pc, sp, ..] = args;
+            let (index, frame_number) = (table_index(index)?, frame(frame_number)?);
+            check_underived(token, thread, l2_table)?;
+            let l2_table = lookup_l2_table(token, thread, l2_table)?;
+            let call = CallCap::new(frame_number, pc, sp, l2_table).ok_or(Error::InvalidFrame)?;
+            give(token, l0_table, index, Cap::Call(call))
+        }
+        label::L0_TABLE_COPY => {
+            let [index, dst, dst_index, ..] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let dst = lookup_l0_table(token, thread, dst)?;
+            l0_table.copy_capability(token, index, &dst, dst_index)?;
+            Ok(0x0)
+        }
+        label::L0_TABLE_MINT => {
+            let [index, dst, dst_index, rights, ..] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let rights = Rights::from_raw(rights).ok_or(Error::InvalidArgument)?;
+            let dst = lookup_l0_table(token, thread, dst)?;
+            l0_table.mint_capability(token, index, &dst, dst_index, rights)?;
+            Ok(0x0)
+        }
+        label::L0_TABLE_MOVE => {
+            let [index, dst, dst_index, ..] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let dst = lookup_l0_table(token, thread, dst)?;
+            l0_table.move_capability(token, index, &dst, dst_index)?;
+            Ok(0x0)
+        }
+        label::L0_TABLE_DELETE => {
+            let [index, ..] = args;
+            let index = table_index(index)?;
+            l0_table.delete_capability(token, index)?;
+            Ok(0x0)
+        }
+        label::L0_TABLE_DERIVE => {
+            let [index, dst, dst_index, frame_number, rights, ..] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let frame_number = frame(frame_number)?;
+            let rights = Rights::from_raw(rights).ok_or(Error::InvalidArgument)?;
+            let dst = lookup_l0_table(token, thread, dst)?;
+            l0_table.derive_capability(token, index, &dst, dst_index, frame_number, rights)?;
+            Ok(0x0)
+        }
+        label::L0_TABLE_BADGE => {
+            // The badged copy is derived from its source, using the second
+            // frame to track the derivation, so that it can be revoked.
+            let [index, dst, dst_index, frame_number, badge, node_frame_number] = args;
+            let (index, dst_index) = (table_index(index)?, table_index(dst_index)?);
+            let (frame_number, node_frame_number) =
+                (frame(frame_number)?, frame(node_frame_number)?);
+            let dst = lookup_l0_table(token, thread, dst)?;
+            let badged = |token: &mut Token, cap| match cap {
+                Cap::Call(call) => call.badged(token, frame_number, badge).map(Cap::Call),
+                Cap::Notification(notification) => notification
+                    .badged(token, frame_number, badge)
+                    .map(Cap::Notification),
+                Cap::Endpoint(endpoint) => endpoint
+                    .badged(token, frame_number, badge)
+                    .map(Cap::Endpoint),
+                _ => Err(Error::InvalidCapability),
+            };
+            l0_table.derive_capability_with(
+                token,
+                index,
+                &dst,
+                dst_index,
+                node_frame_number,
+                Rights::ALL,
+                badged,
+            )?;
+            Ok(0x0)
+        }
+        label::L0_TABLE_REVOKE => {
+            let [index, ..] = args;
+            let index = table_index(index)?;
+            // Report whether the revocation is still incomplete, in which case
+            // it must be invoked again.
+            let complete = l0_table.revoke_capability(token, index, REVOKE_BUDGET)?;
+            Ok(!complete as usize)
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_thread(
+    token: &mut Token,
+    thread: &ThreadCap,
+    target: &ThreadCap,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::THREAD_SET_EXCEPTION_CALL => {
+            let [call, ..] = args;
+            check_underived(token, thread, call)?;
+            let call = lookup_call(token, thread, call)?;
+            target.set_exception_call(token, call);
+            Ok(0x0)
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_call(
+    token: &mut Token,
+    thread: &ThreadCap,
+    call: &CallCap,
+    label: usize,
+    _args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::CALL_CALL => {
+            let badge = thread.call(token, call)?;
+            Ok(badge)
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_console(console: &ConsoleCap, label: usize, _args: [usize; 6]) -> Result<usize> {
+    match label {
+        label::CONSOLE_POLL => {
+            let b = console.read_byte().ok_or(Error::WouldBlock)?;
+            Ok(b.into())
+        }
+        label::CONSOLE_SHUTDOWN => {
+            reset_system(Type::Shutdown, Reason::None).unwrap();
+            unreachable!()
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_irq_control(
+    token: &mut Token,
+    thread: &ThreadCap,
+    irq_control: &IrqControlCap,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::IRQ_CONTROL_GET_HANDLER => {
+            let [line, dst, dst_index, ..] = args;
+            let dst_index = table_index(dst_index)?;
+            let dst = lookup_vacant(token, thread, dst, dst_index)?;
+            let irq_handler = irq_control
+                .handler(token, line)
+                .ok_or(Error::InvalidArgument)?;
+            give(token, &dst, dst_index, Cap::IrqHandler(irq_handler))
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_irq_handler(
+    token: &mut Token,
+    thread: &ThreadCap,
+    irq_handler: &IrqHandlerCap,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    match label {
+        label::IRQ_HANDLER_WAIT => {
+            irq_handler.wait(token, thread)?;
+            Ok(0x0)
+        }
+        label::IRQ_HANDLER_POLL => {
+            if !irq_handler.take(token) {
+                return Err(Error::WouldBlock);
+            }
+            Ok(0x0)
+        }
+        label::IRQ_HANDLER_ACK => {
+            irq_handler.ack(token).ok_or(Error::InvalidState)?;
+            Ok(0x0)
+        }
+        label::IRQ_HANDLER_SET_NOTIFICATION => {
+            let [notification, bits, ..] = args;
+            // The null capability pointer unbinds the line.
+            let notification = if notification == NULL {
+                None
+            } else {
+                check_underived(token, thread, notification)?;
+                Some(lookup_notification(token, thread, notification)?)
+            };
+            irq_handler.bind(token, notification, bits);
+            Ok(0x0)
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_notification(
+    token: &mut Token,
+    thread: &ThreadCap,
+    notification: &NotificationCap,
+    rights: Rights,
+    label: usize,
+    args: [usize; 6],
+) -> Result<usize> {
+    if label != label::NOTIFICATION_SIGNAL && !rights.contains(Rights::CONTROL) {
+        return Err(Error::InsufficientRights);
+    }
+    match label {
+        label::NOTIFICATION_SIGNAL => {
+            let [bits, ..] = args;
+            notification.signal(token, bits);
+            Ok(0x0)
+        }
+        label::NOTIFICATION_WAIT => notification.wait(token, thread),
+        label::NOTIFICATION_POLL => Ok(notification.take(token)),
+        label::NOTIFICATION_SET_TIMER => {
+            // Zero ticks cancels the timer.
+            let [ticks, bits, ..] = args;
+            if ticks == 0x0 {
+                notification.cancel_timer(token);
+            } else {
+                let deadline = plat::time().saturating_add(ticks as u64);
+                notification.set_timer(token, deadline, bits);
+            }
+            Ok(0x0)
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn invoke_endpoint(
+    token: &mut Token,
+    thread: &ThreadCap,
+    endpoint: &EndpointCap,
+    rights: Rights,
+    label: usize,
+) -> Result<usize> {
+    let sending = matches!(label, label::ENDPOINT_SEND | label::ENDPOINT_CALL);
+    if !sending && !rights.contains(Rights::CONTROL) {
+        return Err(Error::InsufficientRights);
+    }
+    // The message is read straight from the thread's registers.
+    match label {
+        label::ENDPOINT_SEND => endpoint.send(token, thread, false),
+        label::ENDPOINT_CALL => endpoint.send(token, thread, true),
+        label::ENDPOINT_RECV => endpoint.recv(token, thread),
+        label::ENDPOINT_REPLY => {
+            endpoint::reply(token, thread)?;
+            Ok(0x0)
+        }
+        label::ENDPOINT_REPLY_RECV => {
+            // Receiving doesn't need a caller to reply to first.
+            let _ = endpoint::reply(token, thread);
+            endpoint.recv(token, thread)
+        }
+        _ => Err(Error::InvalidLabel),
+    }
+}
+
+fn give(token: &mut Token, l0_table: &L0TableCap, index: usize, cap: Cap) -> Result<usize> {
+    l0_table
+        .give_capability(token, index, cap, Rights::ALL)
+        .ok_or(Error::Occupied)?;
+    Ok(0x0)
+}
+
+/// Continue building a page for `thread` with `new`, keeping the span it's
+/// built from for the thread until it retries if it isn't built yet.
+fn build_page<T>(
+    token: &mut Token,
+    thread: &ThreadCap,
+    new: impl FnOnce(Option<PartialSpan>) -> ::core::result::Result<T, SpanError>,
+) -> Result<T> {
+    match new(thread.take_partial_span(token)) {
+        Ok(page) => Ok(page),
+        Err(SpanError::Incomplete(partial)) => {
+            thread.set_partial_span(token, partial);
+            Err(Error::Blocked)
+        }
+        Err(SpanError::InvalidFrame) => Err(Error::InvalidFrame),
+    }
+}
+
+/// Give back an object which was just unmapped.
+///
+/// The rights of the capability it was mapped with aren't recorded, so it only
+/// confers those rights the mapping itself implies.
+fn give_unmapped(
+    token: &mut Token,
+    l0_table: &L0TableCap,
+    index: usize,
+    cap: Cap,
+    rights: Rights,
+) -> Result<usize> {
+    l0_table
+        .give_capability(token, index, cap, rights)
+        .expect("The destination slot should have been checked to be vacant.");
+    Ok(0x0)
+}
+
+fn table_index(index: usize) -> Result<usize> {
+    if index >= TABLE_LEN {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(index)
+}
+
+fn frame(frame_number: usize) -> Result<Idx> {
+    Idx::from_raw(frame_number).ok_or(Error::InvalidArgument)
+}
+
+fn lookup(token: &Token, thread: &ThreadCap, cap_ptr: usize) -> Result<(Cap, Rights)> {
+    let cap = thread.l2_table(token).lookup(token, cap_ptr)?;
+    Ok(cap)
+}
+
+/// Check that the capability at `cap_ptr` isn't derived, before its object is
+/// mapped or held by another object, where revoking it couldn't reach.
+fn check_underived(token: &Token, thread: &ThreadCap, cap_ptr: usize) -> Result<()> {
+    let (l0_table, index) = thread.l2_table(token).lookup_slot(token, cap_ptr)?;
+    if l0_table.is_derived(token, index) {
+        return Err(Error::Derived);
+    }
+    Ok(())
+}
+
+/// Look up a capability which is passed to another object, and so requires
+/// control over it.
+fn lookup_controlled(token: &Token, thread: &ThreadCap, cap_ptr: usize) -> Result<Cap> {
+    let (cap, rights) = lookup(token, thread, cap_ptr)?;
+    if !rights.contains(Rights::CONTROL) {
+        return Err(Error::InsufficientRights);
+    }
+    Ok(cap)
+}
+
+fn lookup_l2_table(token: &Token, thread: &ThreadCap, cap_ptr: usize) -> Result<L2TableCap> {
+    match lookup_controlled(token, thread, cap_ptr)? {
+        Cap::L2Table(l2_table) => Ok(l2_table),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+fn lookup_l1_table(token: &Token, thread: &ThreadCap, cap_ptr: usize) -> Result<L1TableCap> {
+    match lookup_controlled(token, thread, cap_ptr)? {
+        Cap::L1Table(l1_table) => Ok(l1_table),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+fn lookup_l0_table(token: &Token, thread: &ThreadCap, cap_ptr: usize) -> Result<L0TableCap> {
+    match lookup_controlled(token, thread, cap_ptr)? {
+        Cap::L0Table(l0_table) => Ok(l0_table),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+/// Look up the L0 table to receive a capability, checking ahead of time that
+/// the slot at `index` is vacant.
+fn lookup_vacant(
+    token: &Token,
+    thread: &ThreadCap,
+    cap_ptr: usize,
+    index: usize,
+) -> Result<L0TableCap> {
+    let l0_table = lookup_l0_table(token, thread, cap_ptr)?;
+    if !l0_table.is_empty(token, index) {
+        return Err(Error::Occupied);
+    }
+    Ok(l0_table)
+}
+
+/// Look up a page along with its rights, which bound the permissions it can be
+/// mapped with.
+fn lookup_l2_page(
+    token: &Token,
+    thread: &ThreadCap,
+    cap_ptr: usize,
+) -> Result<(L2PageCap, Rights)> {
+    match lookup(token, thread, cap_ptr)? {
+        (Cap::L2Page(l2_page), rights) => Ok((l2_page, rights)),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+/// Look up a page along with its rights, which bound the permissions it can be
+/// mapped with.
+fn lookup_l1_page(
+    token: &Token,
+    thread: &ThreadCap,
+    cap_ptr: usize,
+) -> Result<(L1PageCap, Rights)> {
+    match lookup(token, thread, cap_ptr)? {
+        (Cap::L1Page(l1_page), rights) => Ok((l1_page, rights)),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+/// Look up a page along with its rights, which bound the permissions it can be
+/// mapped with.
+fn lookup_l0_page(
+    token: &Token,
+    thread: &ThreadCap,
+    cap_ptr: usize,
+) -> Result<(NormalPageCap, Rights)> {
+    match lookup(token, thread, cap_ptr)? {
+        (Cap::L0Page(l0_page), rights) => Ok((l0_page, rights)),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+/// Look up a notification to be signalled, which requires no rights.
+fn lookup_notification(
+    token: &Token,
+    thread: &ThreadCap,
+    cap_ptr: usize,
+) -> Result<NotificationCap> {
+    match lookup(token, thread, cap_ptr)? {
+        (Cap::Notification(notification), _) => Ok(notification),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+/// Look up a call, which requires no rights, since it is only ever called.
+fn lookup_call(token: &Token, thread: &ThreadCap, cap_ptr: usize) -> Result<CallCap> {
+    match lookup(token, thread, cap_ptr)? {
+        (Cap::Call(call), _) => Ok(call),
+        _ => Err(Error::InvalidCapability),
+    }
+}
@@ -1,30 +1,52 @@
 // TODO:
-// - Use ASIDs to avoid unecessarily general TLB flushes.
-//     - Does this put policy into the kernel? Can we let user mode control ASID
-//       allocation and just ensure isolation in the kernel, like we do with
-//       frames?
-// - Allow user mode to use accessed, dirty, and global bits.
+// - Allow user mode to use global bits.
 //
 
 use {
     crate::{
+        debug::ConsoleCap,
+        endpoint::EndpointCap,
         frame::{Idx, NormalArc},
-        page::{InternalPageCap, NormalPageCap},
-        sync::{Token, TokenCell},
+        irq::{IrqControlCap, IrqHandlerCap},
+        machine::L0_FRAME_SIZE,
+        notification::NotificationCap,
+        page::{L1PageCap, L2PageCap, NormalPageCap},
+        sbi::rfence,
+        sync::{RacyCell, Token, TokenCell},
         thread::{CallCap, ThreadCap},
     },
-    ::core::cell::Cell,
+    ::core::{
+        cell::Cell,
+        mem::{forget, replace, size_of, transmute, ManuallyDrop},
+        ops::Range,
+        slice,
+        sync::atomic::{
+            AtomicU64, AtomicUsize,
+            Ordering::{Acquire, Relaxed, Release},
+        },
+    },
 };
 
 pub const TABLE_LEN: usize = 0x200;
 
+/// The range of L2 indices which user mode may map.
+pub const USER_L2_INDICES: Range<usize> = 0x1..TABLE_LEN / 2;
+
+#[derive(Clone)]
 pub enum Cap {
     L2Table(L2TableCap),
     L1Table(L1TableCap),
     L0Table(L0TableCap),
-    L0Page(InternalPageCap),
+    L2Page(L2PageCap),
+    L1Page(L1PageCap),
+    L0Page(NormalPageCap),
     Thread(ThreadCap),
     Call(CallCap),
+    Console(ConsoleCap),
+    IrqControl(IrqControlCap),
+    IrqHandler(IrqHandlerCap),
+    Notification(NotificationCap),
+    Endpoint(EndpointCap),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -36,7 +58,60 @@ pub enum Permissions {
     ReadWriteExecute,
 }
 
+impl TryFrom<usize> for Permissions {
+    type Error = ();
+    fn try_from(val: usize) -> Result<Self, Self::Error> {
+        const READ: usize = 0b001;
+        const WRITE: usize = 0b010;
+        const EXECUTE: usize = 0b100;
+        const READ_WRITE: usize = READ | WRITE;
+        const READ_EXECUTE: usize = READ | EXECUTE;
+        const READ_WRITE_EXECUTE: usize = READ | WRITE | EXECUTE;
+        let permissions = match val {
+            READ => Self::ReadOnly,
+            READ_WRITE => Self::ReadWrite,
+            EXECUTE => Self::ExecuteOnly,
+            READ_EXECUTE => Self::ReadExecute,
+            READ_WRITE_EXECUTE => Self::ReadWriteExecute,
+            _ => return Err(()),
+        };
+        Ok(permissions)
+    }
+}
+
 impl Permissions {
+    /// The rights a page capability needs in order to be mapped with these
+    /// permissions.
+    pub const fn rights(&self) -> Rights {
+        match self {
+            Self::ReadOnly => Rights::MAP_READ,
+            Self::ReadWrite => Rights(Rights::MAP_READ.0 | Rights::MAP_WRITE.0),
+            Self::ExecuteOnly => Rights::MAP_EXECUTE,
+            Self::ReadExecute => Rights(Rights::MAP_READ.0 | Rights::MAP_EXECUTE.0),
+            Self::ReadWriteExecute => Rights::MAP_ALL,
+        }
+    }
+
+    /// Decode the permission bits of a leaf entry.
+    const fn from_bits(bits: u64) -> Option<Self> {
+        const READ: u64 = 0b1 << 1;
+        const WRITE: u64 = 0b1 << 2;
+        const EXECUTE: u64 = 0b1 << 3;
+        const READ_WRITE: u64 = READ | WRITE;
+        const READ_EXECUTE: u64 = READ | EXECUTE;
+        const READ_WRITE_EXECUTE: u64 = READ | WRITE | EXECUTE;
+
+        let permissions = match bits & READ_WRITE_EXECUTE {
+            READ => Self::ReadOnly,
+            READ_WRITE => Self::ReadWrite,
+            EXECUTE => Self::ExecuteOnly,
+            READ_EXECUTE => Self::ReadExecute,
+            READ_WRITE_EXECUTE => Self::ReadWriteExecute,
+            _ => return None,
+        };
+        Some(permissions)
+    }
+
     const fn bits(&self) -> u64 {
         const READ: u64 = 0b1 << 1;
         const WRITE: u64 = 0b1 << 2;
@@ -52,23 +127,182 @@ impl Permissions {
     }
 }
 
+/// The kind of memory access which caused a page fault.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    /// Decode the access from the cause of a page fault.
+    pub fn from_page_fault(scause: u64) -> Self {
+        match scause {
+            0xc => Self::Fetch,
+            0xd => Self::Load,
+            0xf => Self::Store,
+            _ => unreachable!("The cause should have been a page fault."),
+        }
+    }
+}
+
+/// The rights a capability slot confers over the object it references.
+///
+/// Rights are stored alongside each capability in its slot, so that copies of
+/// the same capability may confer different rights.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Rights(u8);
+
+impl Rights {
+    /// Map a page with read permission.
+    pub const MAP_READ: Self = Self(0b1 << 0);
+    /// Map a page with write permission.
+    pub const MAP_WRITE: Self = Self(0b1 << 1);
+    /// Map a page with execute permission.
+    pub const MAP_EXECUTE: Self = Self(0b1 << 2);
+    /// Copy the capability into another slot.
+    pub const GRANT: Self = Self(0b1 << 3);
+    /// Invoke operations which modify the referenced object.
+    pub const CONTROL: Self = Self(0b1 << 4);
+
+    pub const NONE: Self = Self(0x0);
+    pub const MAP_ALL: Self = Self(Self::MAP_READ.0 | Self::MAP_WRITE.0 | Self::MAP_EXECUTE.0);
+    pub const ALL: Self = Self(Self::MAP_ALL.0 | Self::GRANT.0 | Self::CONTROL.0);
+
+    pub const fn from_raw(value: usize) -> Option<Self> {
+        if value & !(Self::ALL.0 as usize) != 0 {
+            return None;
+        }
+        Some(Self(value as u8))
+    }
+
+    pub const fn into_raw(self) -> usize {
+        self.0 as usize
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// The reason an operation on a table slot failed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SlotError {
+    /// The source slot does not hold a capability.
+    Empty,
+    /// The destination slot is already in use.
+    Occupied,
+    /// The source slot holds a derived capability, which may only be copied
+    /// by deriving it again.
+    Derived,
+    /// The slot does not hold a derived capability.
+    NotDerived,
+    /// The slot holds a capability with derived children, which must be
+    /// revoked first.
+    HasChildren,
+    /// The frame for the derivation node is not a free frame of normal memory.
+    InvalidFrame,
+    /// The capability does not confer the rights the operation requires.
+    InsufficientRights,
+}
+
+/// The reason a capability address failed to resolve to a capability.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LookupError {
+    /// The address lies outside of the user mappable range.
+    InvalidAddress,
+    /// No L1 table is mapped for the address.
+    MissingL1Table,
+    /// No L0 table is mapped for the address.
+    MissingL0Table,
+    /// The slot for the address maps a page.
+    MappedPage,
+    /// The slot for the address is empty.
+    EmptySlot,
+}
+
 impl Cap {
-    fn l0_entry(self) -> L0Entry {
+    const L2_TABLE_TAG: u8 = 0x0;
+    const L1_TABLE_TAG: u8 = 0x1;
+    const L0_TABLE_TAG: u8 = 0x2;
+    const L2_PAGE_TAG: u8 = 0x3;
+    const L1_PAGE_TAG: u8 = 0x4;
+    const L0_PAGE_TAG: u8 = 0x5;
+    const THREAD_TAG: u8 = 0x6;
+    const CALL_TAG: u8 = 0x7;
+    const CONSOLE_TAG: u8 = 0x9;
+    const IRQ_CONTROL_TAG: u8 = 0xa;
+    const IRQ_HANDLER_TAG: u8 = 0xb;
+    const NOTIFICATION_TAG: u8 = 0xc;
+    const ENDPOINT_TAG: u8 = 0xd;
+
+    fn l0_entry(self, rights: Rights) -> L0Entry {
         let (frame_number, tag) = match self {
-            Self::L2Table(l2_table) => (l2_table.into_frame_number(), 0x0u8),
-            Self::L1Table(l1_table) => (l1_table.into_frame_number(), 0x1u8),
-            Self::L0Table(l0_table) => (l0_table.into_frame_number(), 0x2u8),
-            Self::L0Page(l0_page) => (l0_page.into_frame_number(), 0x5u8),
-            Self::Thread(thread) => (thread.into_frame_number(), 0x6u8),
-            Self::Call(call) => (call.into_frame_number(), 0x7u8),
+            Self::L2Table(l2_table) => (l2_table.into_frame_number(), Self::L2_TABLE_TAG),
+            Self::L1Table(l1_table) => (l1_table.into_frame_number(), Self::L1_TABLE_TAG),
+            Self::L0Table(l0_table) => (l0_table.into_frame_number(), Self::L0_TABLE_TAG),
+            Self::L2Page(l2_page) => (l2_page.into_frame_number(), Self::L2_PAGE_TAG),
+            Self::L1Page(l1_page) => (l1_page.into_frame_number(), Self::L1_PAGE_TAG),
+            Self::L0Page(l0_page) => (l0_page.into_frame_number(), Self::L0_PAGE_TAG),
+            Self::Thread(thread) => (thread.into_frame_number(), Self::THREAD_TAG),
+            Self::Call(call) => (call.into_frame_number(), Self::CALL_TAG),
+            Self::Console(console) => (console.into_frame_number(), Self::CONSOLE_TAG),
+            Self::IrqControl(irq_control) => {
+                (irq_control.into_frame_number(), Self::IRQ_CONTROL_TAG)
+            }
+            Self::IrqHandler(irq_handler) => {
+                (irq_handler.into_frame_number(), Self::IRQ_HANDLER_TAG)
+            }
+            Self::Notification(notification) => {
+                (notification.into_frame_number(), Self::NOTIFICATION_TAG)
+            }
+            Self::Endpoint(endpoint) => (endpoint.into_frame_number(), Self::ENDPOINT_TAG),
         };
-        L0Entry::cap(frame_number, tag)
+        L0Entry::cap(frame_number, tag, rights)
+    }
+
+    /// Reconstruct a capability from the frame number and tag of an entry
+    /// created by `l0_entry`, taking ownership of the entry's reference.
+    ///
+    /// # Safety
+    /// `frame_number` and `tag` must have been encoded by a previous call to
+    /// `l0_entry`.
+    unsafe fn from_l0_entry(frame_number: Idx, tag: u8) -> Self {
+        unsafe {
+            match tag {
+                Self::L2_TABLE_TAG => Self::L2Table(L2TableCap::from_frame_number(frame_number)),
+                Self::L1_TABLE_TAG => Self::L1Table(L1TableCap::from_frame_number(frame_number)),
+                Self::L0_TABLE_TAG => Self::L0Table(L0TableCap::from_frame_number(frame_number)),
+                Self::L2_PAGE_TAG => Self::L2Page(L2PageCap::from_frame_number(frame_number)),
+                Self::L1_PAGE_TAG => Self::L1Page(L1PageCap::from_frame_number(frame_number)),
+                Self::L0_PAGE_TAG => Self::L0Page(NormalPageCap::from_frame_number(frame_number)),
+                Self::THREAD_TAG => Self::Thread(ThreadCap::from_frame_number(frame_number)),
+                Self::CALL_TAG => Self::Call(CallCap::from_frame_number(frame_number)),
+                Self::CONSOLE_TAG => Self::Console(ConsoleCap::from_frame_number(frame_number)),
+                Self::IRQ_CONTROL_TAG => {
+                    Self::IrqControl(IrqControlCap::from_frame_number(frame_number))
+                }
+                Self::IRQ_HANDLER_TAG => {
+                    Self::IrqHandler(IrqHandlerCap::from_frame_number(frame_number))
+                }
+                Self::NOTIFICATION_TAG => {
+                    Self::Notification(NotificationCap::from_frame_number(frame_number))
+                }
+                Self::ENDPOINT_TAG => Self::Endpoint(EndpointCap::from_frame_number(frame_number)),
+                _ => unreachable!("Capability entries should always have a valid tag."),
+            }
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct L2TableCap {
-    entries: NormalArc<TokenCell<[L2Entry; TABLE_LEN]>>,
+    entries: ManuallyDrop<NormalArc<TokenCell<[L2Entry; TABLE_LEN]>>>,
 }
 
 impl ::core::fmt::Debug for L2TableCap {
@@ -79,12 +313,40 @@ impl ::core::fmt::Debug for L2TableCap {
 
 #[derive(Clone)]
 pub struct L1TableCap {
-    entries: NormalArc<TokenCell<[L1Entry; TABLE_LEN]>>,
+    entries: ManuallyDrop<NormalArc<TokenCell<[L1Entry; TABLE_LEN]>>>,
 }
 
 #[derive(Clone)]
 pub struct L0TableCap {
-    entries: NormalArc<TokenCell<[L0Entry; TABLE_LEN]>>,
+    entries: ManuallyDrop<NormalArc<TokenCell<[L0Entry; TABLE_LEN]>>>,
+}
+
+/// A derivation node stores a capability that was derived from another, so
+/// that it can later be revoked.
+///
+/// Derived capabilities are held by their slots indirectly through a node. The
+/// nodes of each derivation tree form a doubly linked list in depth-first
+/// order, so the descendants of a node are exactly the nodes that follow it
+/// with a greater depth. This lets us revoke descendants one at a time with
+/// constant work for each.
+///
+/// All links are strong references. The cycles they form are broken by
+/// unlinking a node before its slot releases it, or before the table holding
+/// its slot is destroyed.
+#[derive(Clone)]
+struct NodeCap {
+    node: NormalArc<TokenCell<Node>>,
+}
+
+struct Node {
+    cap: Cap,
+    depth: usize,
+    /// The table holding the node's slot, which the node doesn't keep alive,
+    /// or `None` once the table has been destroyed.
+    l0_table: Option<Idx>,
+    index: usize,
+    prev: Option<NodeCap>,
+    next: Option<NodeCap>,
 }
 
 pub const fn boot_l2_table() -> [L2Entry; TABLE_LEN] {
@@ -94,15 +356,20 @@ pub const fn boot_l2_table() -> [L2Entry; TABLE_LEN] {
     // `(0xffff_ffc0_0000_0000..=0xffff_ffff_bfff_ffff)`: frame mapped
     // (254GiB)
     // `(0xffff_ffff_c000_0000..=0xffff_ffff_ffff_ffff)`: kernel mapped (2GiB)
-    const INVALID_ENTRY: L2Entry = L2Entry::invalid();
+    //
+    // Entries can't be dropped in a const fn, so we build the raw table first.
+    const INVALID_ENTRY: u64 = L2Entry::invalid().into_raw();
     let mut entries = [INVALID_ENTRY; TABLE_LEN];
     let mut index = TABLE_LEN / 2 + 0x0;
     while index < TABLE_LEN - 1 {
-        entries[index] = unsafe { L2Entry::kernel(index - TABLE_LEN / 2, Permissions::ReadWrite) };
+        entries[index] =
+            unsafe { L2Entry::kernel(index - TABLE_LEN / 2, Permissions::ReadWrite) }.into_raw();
         index += 1;
     }
-    entries[TABLE_LEN - 1] = unsafe { L2Entry::kernel(0x2, Permissions::ReadWriteExecute) };
-    entries
+    entries[TABLE_LEN - 1] =
+        unsafe { L2Entry::kernel(0x2, Permissions::ReadWriteExecute) }.into_raw();
+    // SAFETY: `L2Entry` is a transparent wrapper around `u64`.
+    unsafe { transmute::<[u64; TABLE_LEN], [L2Entry; TABLE_LEN]>(entries) }
 }
 
 static KERNEL_L1_TABLE: TokenCell<Option<L1TableCap>> = TokenCell::new(None);
@@ -112,7 +379,144 @@ pub unsafe fn set_kernel_l1_table(l1_table: L1TableCap, token: &mut Token) {
     *kernel_l1_table = Some(l1_table);
 }
 
-// TODO: Entries should drop capabilities when they are dropped.
+/// The number of ASIDs user mode may choose from, of which zero is reserved for
+/// the kernel.
+pub const ASID_COUNT: usize = 0x1_0000;
+
+// Like frames, user mode allocates ASIDs and the kernel only ensures that no
+// two live L2 tables share one. Each bit records whether its ASID is in use.
+static ASIDS: [AtomicU64; ASID_COUNT / 0x40] = {
+    const INIT: AtomicU64 = AtomicU64::new(0x0);
+    [INIT; ASID_COUNT / 0x40]
+};
+
+/// The word of `ASIDS` holding `asid`'s bit, along with the bit.
+fn asid_bit(asid: usize) -> (&'static AtomicU64, u64) {
+    (&ASIDS[asid / 0x40], 0x1 << (asid % 0x40))
+}
+
+/// The number of ASIDs the hardware implements.
+static HARDWARE_ASID_COUNT: AtomicUsize = AtomicUsize::new(0x1);
+
+/// Determine how many ASIDs the hardware implements.
+///
+/// # Safety
+/// Must be called while the boot table is active.
+pub unsafe fn probe_asids() {
+    let bits = unsafe { crate::plat::probe_asid_bits() };
+    HARDWARE_ASID_COUNT.store(0x1 << bits, Relaxed);
+}
+
+/// The number of frames needed to track which harts use each of the ASIDs the
+/// hardware implements.
+pub fn asid_frame_count() -> usize {
+    let size = HARDWARE_ASID_COUNT.load(Relaxed) * size_of::<AtomicU64>();
+    (size + L0_FRAME_SIZE - 1) / L0_FRAME_SIZE
+}
+
+/// Start tracking which harts use each hardware ASID, in the memory at `addr`,
+/// which spans as many frames as `asid_frame_count` asks for.
+///
+/// # Safety
+/// Must be called exactly once, on boot, after `probe_asids` and before any L2
+/// table is created. The memory must be ours alone, now and ever.
+pub unsafe fn init_asids(addr: *mut ()) {
+    let count = HARDWARE_ASID_COUNT.load(Relaxed);
+    // SAFETY: The caller ensures the memory is ours alone. Zeroed, no hart
+    // uses any ASID.
+    unsafe {
+        addr.cast::<u8>()
+            .write_bytes(0x0, count * size_of::<AtomicU64>())
+    };
+    // SAFETY: The memory is zeroed, suitably aligned, and lives forever.
+    let asid_harts = unsafe { slice::from_raw_parts(addr.cast::<AtomicU64>(), count) };
+    // SAFETY: Nothing else can be looking at the ASIDs yet.
+    unsafe { *ASID_HARTS.borrow_mut() = asid_harts };
+}
+
+/// Whether `asid` may be given to a new L2 table.
+pub fn is_asid_free(asid: usize) -> bool {
+    if !(0x1..ASID_COUNT).contains(&asid) {
+        return false;
+    }
+    let (word, bit) = asid_bit(asid);
+    word.load(Relaxed) & bit == 0x0
+}
+
+/// The ASID that the hardware is given for `asid`.
+///
+/// Address spaces whose ASID is beyond what the hardware implements all share
+/// ASID zero, and so have to be flushed whenever they're activated.
+fn hardware_asid(asid: usize) -> usize {
+    if asid < HARDWARE_ASID_COUNT.load(Relaxed) {
+        asid
+    } else {
+        0x0
+    }
+}
+
+/// The harts which have activated a user address space, and so may cache
+/// translations from any user table.
+static USER_HARTS: AtomicU64 = AtomicU64::new(0x0);
+
+/// For each hardware ASID, the harts which may cache translations tagged with
+/// it, kept in memory set aside on boot.
+static ASID_HARTS: RacyCell<&'static [AtomicU64]> = RacyCell::new(&[]);
+
+/// The harts which may cache translations tagged with the hardware ASID
+/// `asid`.
+fn asid_harts(asid: usize) -> &'static AtomicU64 {
+    // SAFETY: The ASIDs are only modified by `init_asids`, before any L2
+    // table is created.
+    let asid_harts = unsafe { ASID_HARTS.borrow() };
+    &asid_harts[asid]
+}
+
+// Other harts are only started once remote fences are known to be supported.
+const RFENCE_EXPECT: &str = "Remote fences should be supported when there are multiple harts.";
+
+/// The bit which represents the current hart in a set of harts.
+fn hart_bit() -> u64 {
+    let hart_id = crate::sync::hart_id();
+    assert!(hart_id < u64::BITS as u64, "Only 64 harts are supported.");
+    0x1 << hart_id
+}
+
+/// Flush translations from every user address space on every hart which may
+/// cache them.
+///
+/// Tables below L2 may be mapped by any number of address spaces, so changes
+/// to them can't be fenced any more precisely.
+fn shootdown() {
+    crate::plat::sfence_vma();
+    let harts = USER_HARTS.load(Relaxed) & !hart_bit();
+    if harts != 0x0 {
+        rfence::remote_sfence_vma(harts as usize, 0x0, 0x0, usize::MAX).expect(RFENCE_EXPECT);
+    }
+}
+
+/// Flush translations for `addrs` tagged with the hardware ASID `asid` on every
+/// hart which may cache them.
+fn shootdown_asid(asid: usize, addrs: Range<usize>) {
+    crate::plat::sfence_vma_asid(asid);
+    let harts = asid_harts(asid).load(Relaxed) & !hart_bit();
+    if harts != 0x0 {
+        rfence::remote_sfence_vma_asid(harts as usize, 0x0, addrs.start, addrs.len(), asid)
+            .expect(RFENCE_EXPECT);
+    }
+}
+
+const SATP_MODE_SV39: u64 = 0x8000_0000_0000_0000u64;
+const SATP_PPN_MASK: u64 = (0x1 << 44) - 1;
+
+// Entries own a reference to the object they map or hold, which is released
+// when they are overwritten or dropped. Global leaf entries are the exception:
+// they map the kernel itself, and are never backed by a reference.
+//
+// Destroying a table releases the references of all its entries, which may
+// destroy the tables they refer to in turn, and so on without bound. So that
+// this doesn't recurse, tables are released through `release_table`, and only
+// destroyed by `destroy_dead_tables`.
 
 #[repr(transparent)]
 pub struct L2Entry(u64);
@@ -124,50 +528,220 @@ struct L1Entry(u64);
 struct L0Entry(u64);
 
 impl L2TableCap {
-    pub fn activate(self) {
+    pub fn activate(self, token: &Token) {
         #[thread_local]
         static BOOTSTRAPPED: Cell<bool> = Cell::new(false);
 
-        // TODO: We should be more precise about our fences.
-        // TODO: We need to do remote fences for the other harts.
-
-        if BOOTSTRAPPED.replace(true) {
-            let mut satp = 0x0;
-            satp |= self.entries.into_raw().into_raw() as u64;
-            const SATP_MODE_SV39: u64 = 0x8000_0000_0000_0000u64;
-            satp |= SATP_MODE_SV39;
-            satp = unsafe { crate::plat::swap_satp(satp) };
-            let entries: NormalArc<TokenCell<[L2Entry; TABLE_LEN]>> = unsafe {
-                NormalArc::from_raw(Idx::from_raw((satp & !SATP_MODE_SV39) as usize).unwrap())
-            };
-            drop(entries);
-        } else {
-            let mut satp = 0x0;
-            satp |= self.entries.into_raw().into_raw() as u64;
-            const SATP_MODE_SV39: u64 = 0x8000_0000_0000_0000u64;
-            satp |= SATP_MODE_SV39;
+        let asid = hardware_asid(self.asid(token));
+        let frame_number = self.into_frame_number();
+        let mut satp = 0x0;
+        satp |= frame_number.into_raw() as u64;
+        satp |= (asid as u64) << 44;
+        satp |= SATP_MODE_SV39;
+
+        // From here on, this hart may cache the table's translations.
+        USER_HARTS.fetch_or(hart_bit(), Relaxed);
+        asid_harts(asid).fetch_or(hart_bit(), Relaxed);
+
+        if !BOOTSTRAPPED.replace(true) {
             unsafe { crate::plat::set_satp(satp) };
+        } else if crate::plat::satp() == satp {
+            // The table is already active, and so already referenced by satp.
+            drop(unsafe { Self::from_frame_number(frame_number) });
+            return;
+        } else {
+            let satp = unsafe { crate::plat::swap_satp(satp) };
+            let frame_number = Idx::from_raw((satp & SATP_PPN_MASK) as usize).unwrap();
+            drop(unsafe { Self::from_frame_number(frame_number) });
+        }
+
+        // Translations for the shared ASID may belong to another address
+        // space. Otherwise the ASID's translations can only be our own.
+        if asid == 0x0 {
+            crate::plat::sfence_vma_asid(asid);
         }
     }
 
-    pub fn new(frame_number: Idx, token: &Token) -> Option<Self> {
+    /// Create a new L2 table with the otherwise unused `asid`, which must be
+    /// nonzero.
+    pub fn new(frame_number: Idx, asid: usize, token: &Token) -> Option<Self> {
+        if !(0x1..ASID_COUNT).contains(&asid) {
+            return None;
+        }
+        // ORDERING: Any previous use of the ASID must happen strictly before
+        // this one.
+        let (word, bit) = asid_bit(asid);
+        if word.fetch_or(bit, Acquire) & bit != 0x0 {
+            return None;
+        }
         let mut l2_entries = boot_l2_table();
+        // From here on, dropping the entries releases the ASID.
+        l2_entries[0x0] = L2Entry::asid(asid);
         let kernel_l1_table = KERNEL_L1_TABLE.borrow(&token);
         let kernel_l1_table = kernel_l1_table.clone().unwrap();
         l2_entries[TABLE_LEN - 1] = L2Entry::kernel_interior(kernel_l1_table);
         let entries = NormalArc::new(frame_number, TokenCell::new(l2_entries))?;
+        let entries = ManuallyDrop::new(entries);
+        // Translations may remain from the ASID's previous L2 table.
+        let asid = hardware_asid(asid);
+        shootdown_asid(asid, 0x0..usize::MAX);
+        if asid != 0x0 {
+            asid_harts(asid).store(0x0, Relaxed);
+        }
         Some(Self { entries })
     }
 
-    pub fn map_l1_table(&self, token: &mut Token, index: usize, l1_table: L1TableCap) {
-        assert!(index > 0);
-        assert!(index < TABLE_LEN / 2);
+    /// The ASID this table was created with.
+    pub fn asid(&self, token: &Token) -> usize {
+        self.entries.borrow(token)[0x0]
+            .to_asid()
+            .expect("L2 tables should always record their ASID.")
+    }
+
+    /// Flush this address space's translations for the L2 entry at `index`,
+    /// including any cached while it was invalid.
+    fn fence(&self, token: &Token, index: usize) {
+        let asid = hardware_asid(self.asid(token));
+        let addr = index << 30;
+        shootdown_asid(asid, addr..addr + crate::machine::L2_FRAME_SIZE);
+    }
+
+    pub fn map_l1_table(
+        &self,
+        token: &mut Token,
+        index: usize,
+        l1_table: L1TableCap,
+    ) -> Option<()> {
+        assert!(USER_L2_INDICES.contains(&index));
         let entries = self.entries.borrow_mut(token);
+        if !entries[index].is_invalid() {
+            return None;
+        }
         entries[index] = L2Entry::interior(l1_table);
+        self.fence(token, index);
+        Some(())
+    }
+
+    /// Unmap the L1 table at `index`, handing back the table's reference.
+    pub fn unmap_l1_table(&self, token: &mut Token, index: usize) -> Option<L1TableCap> {
+        assert!(USER_L2_INDICES.contains(&index));
+        let entries = self.entries.borrow_mut(token);
+        let l1_table = entries[index].to_interior()?;
+        entries[index] = L2Entry::invalid();
+        self.fence(token, index);
+        Some(l1_table)
+    }
+
+    /// Map `l2_page` at `index` with `permissions`, which must be allowed by
+    /// the page capability's `rights`.
+    pub fn map_l2_page(
+        &self,
+        token: &mut Token,
+        index: usize,
+        l2_page: L2PageCap,
+        rights: Rights,
+        permissions: Permissions,
+    ) -> Result<(), SlotError> {
+        assert!(USER_L2_INDICES.contains(&index));
+        if !rights.contains(permissions.rights()) {
+            return Err(SlotError::InsufficientRights);
+        }
+        let entries = self.entries.borrow_mut(token);
+        if !entries[index].is_invalid() {
+            return Err(SlotError::Occupied);
+        }
+        entries[index] = L2Entry::leaf(l2_page, permissions);
+        self.fence(token, index);
+        Ok(())
+    }
+
+    /// Unmap the page at `index`, handing back the page's reference along with
+    /// the permissions it was mapped with.
+    pub fn unmap_l2_page(
+        &self,
+        token: &mut Token,
+        index: usize,
+    ) -> Option<(L2PageCap, Permissions)> {
+        assert!(USER_L2_INDICES.contains(&index));
+        let entries = self.entries.borrow_mut(token);
+        let leaf = entries[index].to_leaf()?;
+        entries[index] = L2Entry::invalid();
+        self.fence(token, index);
+        Some(leaf)
+    }
+
+    /// Resolve a user capability address to the L0 table and index of its
+    /// slot by walking the table tree.
+    ///
+    /// Capability addresses are user virtual addresses. Each L0 frame of the
+    /// address space corresponds to a single slot, so the offset within the
+    /// frame is ignored.
+    pub fn lookup_slot(
+        &self,
+        token: &Token,
+        addr: usize,
+    ) -> Result<(L0TableCap, usize), LookupError> {
+        let l2_index = addr >> 30;
+        let l1_index = (addr >> 21) & (TABLE_LEN - 1);
+        let l0_index = (addr >> 12) & (TABLE_LEN - 1);
+        if !USER_L2_INDICES.contains(&l2_index) {
+            return Err(LookupError::InvalidAddress);
+        }
+        let l1_table = self.entries.borrow(token)[l2_index]
+            .to_interior()
+            .ok_or(LookupError::MissingL1Table)?;
+        let l0_table = l1_table.entries.borrow(token)[l1_index]
+            .to_interior()
+            .ok_or(LookupError::MissingL0Table)?;
+        Ok((l0_table, l0_index))
+    }
+
+    /// Set the accessed bit of the page mapped at `addr`, and its dirty bit
+    /// for stores, provided that the mapping permits `access`.
+    ///
+    /// Harts which don't update these bits themselves raise a page fault
+    /// instead, which we resolve here. Only L0 pages are mapped with the bits
+    /// clear.
+    pub fn update_accessed_dirty(
+        &self,
+        token: &mut Token,
+        addr: usize,
+        access: Access,
+    ) -> Option<()> {
+        let (l0_table, index) = self.lookup_slot(token, addr).ok()?;
+        l0_table.entries.borrow_mut(token)[index].update_accessed_dirty(access)?;
+        // Flush the translation the hart faulted on, which is stale whether or
+        // not the bits were already set.
+        let asid = hardware_asid(self.asid(token));
+        crate::plat::sfence_vma_addr_asid(addr, asid);
+        Some(())
+    }
+
+    /// Resolve a user capability address to a borrowed copy of the capability
+    /// stored in its slot, along with the rights the slot confers.
+    pub fn lookup(&self, token: &Token, addr: usize) -> Result<(Cap, Rights), LookupError> {
+        let (l0_table, index) = self.lookup_slot(token, addr)?;
+        if l0_table.entries.borrow(token)[index].is_leaf() {
+            return Err(LookupError::MappedPage);
+        }
+        l0_table
+            .fetch_capability(token, index)
+            .ok_or(LookupError::EmptySlot)
     }
 
     pub fn into_frame_number(self) -> Idx {
-        self.entries.into_raw()
+        let mut table = ManuallyDrop::new(self);
+        // SAFETY: The table is never touched again.
+        unsafe { ManuallyDrop::take(&mut table.entries) }.into_raw()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let entries = unsafe { NormalArc::from_raw(frame_number) };
+        let entries = ManuallyDrop::new(entries);
+        Self { entries }
     }
 }
 
@@ -175,12 +749,70 @@ impl L1TableCap {
     pub fn new(frame_number: Idx) -> Option<Self> {
         const INVALID_ENTRY: L1Entry = L1Entry::invalid();
         let entries = NormalArc::new(frame_number, TokenCell::new([INVALID_ENTRY; TABLE_LEN]))?;
+        let entries = ManuallyDrop::new(entries);
         Some(Self { entries })
     }
 
-    pub fn map_l0_table(&self, token: &mut Token, index: usize, l0_table: L0TableCap) {
+    pub fn map_l0_table(
+        &self,
+        token: &mut Token,
+        index: usize,
+        l0_table: L0TableCap,
+    ) -> Option<()> {
         let entries = self.entries.borrow_mut(token);
+        if !entries[index].is_invalid() {
+            return None;
+        }
         entries[index] = L1Entry::interior(l0_table);
+        // The entry may have been cached while it was invalid.
+        shootdown();
+        Some(())
+    }
+
+    /// Unmap the L0 table at `index`, handing back the table's reference.
+    pub fn unmap_l0_table(&self, token: &mut Token, index: usize) -> Option<L0TableCap> {
+        let entries = self.entries.borrow_mut(token);
+        let l0_table = entries[index].to_interior()?;
+        entries[index] = L1Entry::invalid();
+        shootdown();
+        Some(l0_table)
+    }
+
+    /// Map `l1_page` at `index` with `permissions`, which must be allowed by
+    /// the page capability's `rights`.
+    pub fn map_l1_page(
+        &self,
+        token: &mut Token,
+        index: usize,
+        l1_page: L1PageCap,
+        rights: Rights,
+        permissions: Permissions,
+    ) -> Result<(), SlotError> {
+        if !rights.contains(permissions.rights()) {
+            return Err(SlotError::InsufficientRights);
+        }
+        let entries = self.entries.borrow_mut(token);
+        if !entries[index].is_invalid() {
+            return Err(SlotError::Occupied);
+        }
+        entries[index] = L1Entry::leaf(l1_page, permissions);
+        // The entry may have been cached while it was invalid.
+        shootdown();
+        Ok(())
+    }
+
+    /// Unmap the page at `index`, handing back the page's reference along with
+    /// the permissions it was mapped with.
+    pub fn unmap_l1_page(
+        &self,
+        token: &mut Token,
+        index: usize,
+    ) -> Option<(L1PageCap, Permissions)> {
+        let entries = self.entries.borrow_mut(token);
+        let leaf = entries[index].to_leaf()?;
+        entries[index] = L1Entry::invalid();
+        shootdown();
+        Some(leaf)
     }
 
     pub fn map_l0_kernel_table(&self, token: &mut Token, index: usize, l0_table: L0TableCap) {
@@ -189,7 +821,18 @@ impl L1TableCap {
     }
 
     pub fn into_frame_number(self) -> Idx {
-        self.entries.into_raw()
+        let mut table = ManuallyDrop::new(self);
+        // SAFETY: The table is never touched again.
+        unsafe { ManuallyDrop::take(&mut table.entries) }.into_raw()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let entries = unsafe { NormalArc::from_raw(frame_number) };
+        let entries = ManuallyDrop::new(entries);
+        Self { entries }
     }
 }
 
@@ -197,40 +840,392 @@ impl L0TableCap {
     pub fn new(frame_number: Idx) -> Option<Self> {
         const INVALID_ENTRY: L0Entry = L0Entry::invalid();
         let entries = NormalArc::new(frame_number, TokenCell::new([INVALID_ENTRY; TABLE_LEN]))?;
+        let entries = ManuallyDrop::new(entries);
         Some(Self { entries })
     }
 
+    /// Map `l0_page` at `index` with `permissions`, which must be allowed by
+    /// the page capability's `rights`.
     pub fn map_l0_page(
         &self,
         token: &mut Token,
         index: usize,
         l0_page: NormalPageCap,
+        rights: Rights,
         permissions: Permissions,
-    ) {
+    ) -> Result<(), SlotError> {
+        if !rights.contains(permissions.rights()) {
+            return Err(SlotError::InsufficientRights);
+        }
         let entries = self.entries.borrow_mut(token);
+        if !entries[index].is_invalid() {
+            return Err(SlotError::Occupied);
+        }
         entries[index] = L0Entry::leaf(l0_page, permissions);
+        // The entry may have been cached while it was invalid.
+        shootdown();
+        Ok(())
+    }
+
+    /// Read and clear the accessed and dirty bits of the pages mapped at
+    /// `indices`, which may span at most half as many entries as there are
+    /// bits in a word.
+    ///
+    /// The accessed bit for the `n`th index is returned in bit `2 * n`, and the
+    /// dirty bit in bit `2 * n + 1`. Indices which don't map a page read as
+    /// neither.
+    pub fn take_accessed_dirty(&self, token: &mut Token, indices: Range<usize>) -> usize {
+        assert!(indices.len() <= usize::BITS as usize / 2);
+        let mut bits = 0x0;
+        for (n, entry) in self.entries.borrow_mut(token)[indices]
+            .iter_mut()
+            .enumerate()
+        {
+            bits |= entry.take_accessed_dirty() << (2 * n);
+        }
+        // The entries may have been cached with the bits set.
+        shootdown();
+        bits
+    }
+
+    /// Unmap the page at `index`, handing back the page's reference along with
+    /// the permissions it was mapped with.
+    pub fn unmap_l0_page(
+        &self,
+        token: &mut Token,
+        index: usize,
+    ) -> Option<(NormalPageCap, Permissions)> {
+        let entries = self.entries.borrow_mut(token);
+        let leaf = entries[index].to_leaf()?;
+        entries[index] = L0Entry::invalid();
+        shootdown();
+        Some(leaf)
+    }
+
+    /// Whether the slot at `index` neither maps a page nor holds a capability.
+    pub fn is_empty(&self, token: &Token, index: usize) -> bool {
+        self.entries.borrow(token)[index].is_invalid()
     }
 
+    /// Whether the slot at `index` holds a derived capability.
+    pub fn is_derived(&self, token: &Token, index: usize) -> bool {
+        self.entries.borrow(token)[index].to_node().is_some()
+    }
+
+    /// Map a frame of the kernel image, which no frame object ever refers to.
+    ///
+    /// # Safety
+    /// `frame_number` must be part of the kernel image, and reserved, so that
+    /// nothing else ever maps it.
     pub unsafe fn map_l0_kernel_page(
         &self,
         token: &mut Token,
         index: usize,
-        l0_page: InternalPageCap,
+        frame_number: Idx,
         permissions: Permissions,
     ) {
         let entries = self.entries.borrow_mut(token);
-        entries[index] = unsafe { L0Entry::kernel_leaf(l0_page, permissions) };
+        entries[index] = unsafe { L0Entry::kernel_leaf(frame_number, permissions) };
     }
 
-    pub fn give_capability(&self, token: &mut Token, index: usize, cap: Cap) {
+    pub fn give_capability(
+        &self,
+        token: &mut Token,
+        index: usize,
+        cap: Cap,
+        rights: Rights,
+    ) -> Option<()> {
         let entries = self.entries.borrow_mut(token);
-        entries[index] = cap.l0_entry();
+        if !entries[index].is_invalid() {
+            return None;
+        }
+        entries[index] = cap.l0_entry(rights);
+        Some(())
+    }
+
+    /// Borrow a copy of the capability in the slot at `index`, along with the
+    /// rights the slot confers.
+    pub fn fetch_capability(&self, token: &Token, index: usize) -> Option<(Cap, Rights)> {
+        let entry = &self.entries.borrow(token)[index];
+        let cap = entry.to_cap(token)?;
+        Some((cap, entry.rights()))
+    }
+
+    /// Copy the capability in the slot at `index` into the empty slot at
+    /// `dst_index` of `dst`, which may be this same table.
+    pub fn copy_capability(
+        &self,
+        token: &mut Token,
+        index: usize,
+        dst: &L0TableCap,
+        dst_index: usize,
+    ) -> Result<(), SlotError> {
+        self.mint_capability(token, index, dst, dst_index, Rights::ALL)
+    }
+
+    /// Copy the capability in the slot at `index` into the empty slot at
+    /// `dst_index` of `dst`, conferring only those of its rights which are
+    /// also in `rights`.
+    ///
+    /// The source must confer the right to grant it. Derived capabilities
+    /// cannot be copied, since the copy would escape revocation.
+    pub fn mint_capability(
+        &self,
+        token: &mut Token,
+        index: usize,
+        dst: &L0TableCap,
+        dst_index: usize,
+        rights: Rights,
+    ) -> Result<(), SlotError> {
+        if self.entries.borrow(token)[index].to_node().is_some() {
+            return Err(SlotError::Derived);
+        }
+        let (cap, src_rights) = self
+            .fetch_capability(token, index)
+            .ok_or(SlotError::Empty)?;
+        if !src_rights.contains(Rights::GRANT) {
+            return Err(SlotError::InsufficientRights);
+        }
+        dst.give_capability(token, dst_index, cap, src_rights.intersection(rights))
+            .ok_or(SlotError::Occupied)
+    }
+
+    /// Derive a revocable copy of the capability in the slot at `index` into
+    /// the empty slot at `dst_index` of `dst`, using the frame at
+    /// `frame_number` to track the derivation.
+    ///
+    /// If the source is itself derived, the copy becomes its child. Otherwise
+    /// the copy becomes the root of a new derivation tree. As with minting,
+    /// the source must confer the right to grant it, and the copy only
+    /// confers those of its rights which are also in `rights`.
+    pub fn derive_capability(
+        &self,
+        token: &mut Token,
+        index: usize,
+        dst: &L0TableCap,
+        dst_index: usize,
+        frame_number: Idx,
+        rights: Rights,
+    ) -> Result<(), SlotError> {
+        let derive = |_: &mut Token, cap| Ok::<_, SlotError>(cap);
+        self.derive_capability_with(token, index, dst, dst_index, frame_number, rights, derive)
     }
 
-    // TODO: allow cloning, revoking, and fetching capabilities.
+    /// Derive the capability which `derive` makes from the one in the slot at
+    /// `index`, as [`derive_capability`](Self::derive_capability) would a
+    /// copy.
+    ///
+    /// This lets objects made from a capability, such as its badged copies, be
+    /// revoked along with it.
+    pub fn derive_capability_with<E: From<SlotError>>(
+        &self,
+        token: &mut Token,
+        index: usize,
+        dst: &L0TableCap,
+        dst_index: usize,
+        frame_number: Idx,
+        rights: Rights,
+        derive: impl FnOnce(&mut Token, Cap) -> Result<Cap, E>,
+    ) -> Result<(), E> {
+        if !dst.entries.borrow(token)[dst_index].is_invalid() {
+            return Err(SlotError::Occupied.into());
+        }
+        let entry = &self.entries.borrow(token)[index];
+        let cap = entry.to_cap(token).ok_or(SlotError::Empty)?;
+        let src_rights = entry.rights();
+        if !src_rights.contains(Rights::GRANT) {
+            return Err(SlotError::InsufficientRights.into());
+        }
+        let parent = entry.to_node();
+        let cap = derive(token, cap)?;
+        let (depth, next) = match &parent {
+            Some(parent) => {
+                let parent = parent.node.borrow(token);
+                (parent.depth + 1, parent.next.clone())
+            }
+            None => (0, None),
+        };
+        let node = Node {
+            cap,
+            depth,
+            l0_table: Some(dst.frame_number()),
+            index: dst_index,
+            prev: parent.clone(),
+            next: next.clone(),
+        };
+        let node =
+            NormalArc::new(frame_number, TokenCell::new(node)).ok_or(SlotError::InvalidFrame)?;
+        let node = NodeCap { node };
+        if let Some(next) = next {
+            next.node.borrow_mut(token).prev = Some(node.clone());
+        }
+        if let Some(parent) = parent {
+            parent.node.borrow_mut(token).next = Some(node.clone());
+        }
+        dst.entries.borrow_mut(token)[dst_index] =
+            L0Entry::node(node, src_rights.intersection(rights));
+        Ok(())
+    }
+
+    /// Revoke the derived capability in the slot at `index` by deleting up to
+    /// `budget` of its descendants.
+    ///
+    /// Returns whether the revocation is complete. If not, it may be continued
+    /// by revoking again.
+    pub fn revoke_capability(
+        &self,
+        token: &mut Token,
+        index: usize,
+        budget: usize,
+    ) -> Result<bool, SlotError> {
+        let node = self.entries.borrow(token)[index]
+            .to_node()
+            .ok_or(SlotError::NotDerived)?;
+        for _ in 0..budget {
+            if let Some(child) = node.first_child(token) {
+                child.remove(token);
+            } else {
+                return Ok(true);
+            }
+        }
+        Ok(node.first_child(token).is_none())
+    }
+
+    /// Move the capability in the slot at `index` into the empty slot at
+    /// `dst_index` of `dst`, which may be this same table.
+    ///
+    /// The reference held by the slot moves along with it.
+    pub fn move_capability(
+        &self,
+        token: &mut Token,
+        index: usize,
+        dst: &L0TableCap,
+        dst_index: usize,
+    ) -> Result<(), SlotError> {
+        if !self.entries.borrow(token)[index].is_cap() {
+            return Err(SlotError::Empty);
+        }
+        if !dst.entries.borrow(token)[dst_index].is_invalid() {
+            return Err(SlotError::Occupied);
+        }
+        if let Some(node) = self.entries.borrow(token)[index].to_node() {
+            let node = node.node.borrow_mut(token);
+            node.l0_table = Some(dst.frame_number());
+            node.index = dst_index;
+        }
+        let entry = replace(
+            &mut self.entries.borrow_mut(token)[index],
+            L0Entry::invalid(),
+        );
+        dst.entries.borrow_mut(token)[dst_index] = entry;
+        Ok(())
+    }
+
+    /// Delete the capability in the slot at `index`, releasing the slot's
+    /// reference to the underlying object.
+    ///
+    /// Derived capabilities can only be deleted once they have no children.
+    pub fn delete_capability(&self, token: &mut Token, index: usize) -> Result<(), SlotError> {
+        let entry = &self.entries.borrow(token)[index];
+        if !entry.is_cap() {
+            return Err(SlotError::Empty);
+        }
+        if let Some(node) = entry.to_node() {
+            if node.first_child(token).is_some() {
+                return Err(SlotError::HasChildren);
+            }
+            node.remove(token);
+        } else {
+            let entry = replace(
+                &mut self.entries.borrow_mut(token)[index],
+                L0Entry::invalid(),
+            );
+            drop(entry);
+        }
+        Ok(())
+    }
 
     pub fn into_frame_number(self) -> Idx {
-        self.entries.into_raw()
+        let mut table = ManuallyDrop::new(self);
+        // SAFETY: The table is never touched again.
+        unsafe { ManuallyDrop::take(&mut table.entries) }.into_raw()
+    }
+
+    /// The frame the table lives in, which refers to the table without a
+    /// reference.
+    fn frame_number(&self) -> Idx {
+        self.entries.idx()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let entries = unsafe { NormalArc::from_raw(frame_number) };
+        let entries = ManuallyDrop::new(entries);
+        Self { entries }
+    }
+}
+
+impl NodeCap {
+    const TAG: u8 = 0x8;
+
+    /// Find the first of this node's children, if any.
+    fn first_child(&self, token: &Token) -> Option<NodeCap> {
+        let node = self.node.borrow(token);
+        let next = node.next.as_ref()?;
+        if next.node.borrow(token).depth <= node.depth {
+            return None;
+        }
+        Some(next.clone())
+    }
+
+    /// Unlink this node from its derivation tree and clear its slot, releasing
+    /// the slot's reference.
+    ///
+    /// Any children of this node are left to be adopted by its parent.
+    fn remove(self, token: &mut Token) {
+        // A linked node's table is alive unless it has died on this hart, in
+        // which case destroying it unlinks the node instead.
+        destroy_dead_tables(token);
+        self.unlink(token);
+        let node = self.node.borrow(token);
+        let (l0_table, index) = match node.l0_table {
+            Some(l0_table) => (l0_table, node.index),
+            None => return,
+        };
+        // SAFETY: The table is alive, and we only borrow its reference.
+        let l0_table = ManuallyDrop::new(unsafe { L0TableCap::from_frame_number(l0_table) });
+        let entry = replace(
+            &mut l0_table.entries.borrow_mut(token)[index],
+            L0Entry::invalid(),
+        );
+        drop(entry);
+    }
+
+    /// Unlink this node from its derivation tree, leaving its slot be.
+    fn unlink(&self, token: &mut Token) {
+        let node = self.node.borrow_mut(token);
+        let prev = node.prev.take();
+        let next = node.next.take();
+        if let Some(prev) = &prev {
+            prev.node.borrow_mut(token).next = next.clone();
+        }
+        if let Some(next) = &next {
+            next.node.borrow_mut(token).prev = prev;
+        }
+    }
+
+    fn into_frame_number(self) -> Idx {
+        self.node.into_raw()
+    }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let node = unsafe { NormalArc::from_raw(frame_number) };
+        Self { node }
     }
 }
 
@@ -248,8 +1243,21 @@ impl L2Entry {
         Self(VALID | permissions | USER | GLOBAL | ACCESSED | DIRTY | RSW | ppn)
     }
 
+    pub fn leaf(l2_page: L2PageCap, permissions: Permissions) -> Self {
+        let frame_number = l2_page.into_frame_number().into_raw() as u64;
+        const VALID: u64 = 0b1 << 0;
+        let permissions = permissions.bits();
+        const USER: u64 = 0b1 << 4;
+        const GLOBAL: u64 = 0b0 << 5;
+        const ACCESSED: u64 = 0b1 << 6;
+        const DIRTY: u64 = 0b1 << 7;
+        const RSW: u64 = 0b00 << 8;
+        let ppn = (frame_number & ((1 << 44) - 1)) << 10;
+        Self(VALID | permissions | USER | GLOBAL | ACCESSED | DIRTY | RSW | ppn)
+    }
+
     pub fn interior(l1_table: L1TableCap) -> Self {
-        let frame_number = l1_table.entries.into_raw().into_raw() as u64;
+        let frame_number = l1_table.into_frame_number().into_raw() as u64;
         const VALID: u64 = 0b1 << 0;
         const RSW: u64 = 0b00 << 8;
         let ppn = (frame_number & ((1 << 44) - 1)) << 10;
@@ -257,7 +1265,7 @@ impl L2Entry {
     }
 
     pub fn kernel_interior(l1_table: L1TableCap) -> Self {
-        let frame_number = l1_table.entries.into_raw().into_raw() as u64;
+        let frame_number = l1_table.into_frame_number().into_raw() as u64;
         const VALID: u64 = 0b1 << 0;
         const GLOBAL: u64 = 0b1 << 5;
         const RSW: u64 = 0b00 << 8;
@@ -270,11 +1278,70 @@ impl L2Entry {
         const DONT_CARE: u64 = 0x0 << 1;
         Self(VALID | DONT_CARE)
     }
+
+    /// An invalid entry which records its table's ASID, and owns it. This is
+    /// only stored at index zero, which user mode can't map.
+    const fn asid(asid: usize) -> Self {
+        const VALID: u64 = 0b0 << 0;
+        const ASID: u64 = 0b1 << 1;
+        let asid = (asid as u64 & 0xffff) << 10;
+        Self(VALID | ASID | asid)
+    }
+
+    pub const fn is_invalid(&self) -> bool {
+        const VALID: u64 = 0b1 << 0;
+        self.0 & VALID == 0
+    }
+
+    fn to_asid(&self) -> Option<usize> {
+        const VALID: u64 = 0b1 << 0;
+        const ASID: u64 = 0b1 << 1;
+        if self.0 & (VALID | ASID) != ASID {
+            return None;
+        }
+        Some(((self.0 >> 10) & 0xffff) as usize)
+    }
+
+    /// Take the raw entry, without releasing its reference.
+    const fn into_raw(self) -> u64 {
+        let entry = self.0;
+        forget(self);
+        entry
+    }
+
+    /// Borrow the L1 table referenced by an interior entry.
+    fn to_interior(&self) -> Option<L1TableCap> {
+        let frame_number = interior_frame_number(self.0)?;
+        let l1_table = ManuallyDrop::new(unsafe { L1TableCap::from_frame_number(frame_number) });
+        Some(L1TableCap::clone(&l1_table))
+    }
+
+    /// Borrow the page mapped by a user leaf entry, along with its
+    /// permissions.
+    fn to_leaf(&self) -> Option<(L2PageCap, Permissions)> {
+        let frame_number = user_leaf_frame_number(self.0)?;
+        let permissions = Permissions::from_bits(self.0)?;
+        let l2_page = ManuallyDrop::new(unsafe { L2PageCap::from_frame_number(frame_number) });
+        Some((L2PageCap::clone(&l2_page), permissions))
+    }
 }
 
 impl L1Entry {
+    pub fn leaf(l1_page: L1PageCap, permissions: Permissions) -> Self {
+        let frame_number = l1_page.into_frame_number().into_raw() as u64;
+        const VALID: u64 = 0b1 << 0;
+        let permissions = permissions.bits();
+        const USER: u64 = 0b1 << 4;
+        const GLOBAL: u64 = 0b0 << 5;
+        const ACCESSED: u64 = 0b1 << 6;
+        const DIRTY: u64 = 0b1 << 7;
+        const RSW: u64 = 0b00 << 8;
+        let ppn = (frame_number & ((1 << 44) - 1)) << 10;
+        Self(VALID | permissions | USER | GLOBAL | ACCESSED | DIRTY | RSW | ppn)
+    }
+
     pub fn interior(l0_table: L0TableCap) -> Self {
-        let frame_number = l0_table.entries.into_raw().into_raw() as u64;
+        let frame_number = l0_table.into_frame_number().into_raw() as u64;
         const VALID: u64 = 0b1 << 0;
         const RSW: u64 = 0b00 << 8;
         let ppn = (frame_number & ((1 << 44) - 1)) << 10;
@@ -282,7 +1349,7 @@ impl L1Entry {
     }
 
     pub unsafe fn kernel_interior(l0_table: L0TableCap) -> Self {
-        let frame_number = l0_table.entries.into_raw().into_raw() as u64;
+        let frame_number = l0_table.into_frame_number().into_raw() as u64;
         const VALID: u64 = 0b1 << 0;
         const GLOBAL: u64 = 0b1 << 5;
         const RSW: u64 = 0b00 << 8;
@@ -295,6 +1362,27 @@ impl L1Entry {
         const DONT_CARE: u64 = 0x0 << 1;
         Self(VALID | DONT_CARE)
     }
+
+    pub const fn is_invalid(&self) -> bool {
+        const VALID: u64 = 0b1 << 0;
+        self.0 & VALID == 0
+    }
+
+    /// Borrow the L0 table referenced by an interior entry.
+    fn to_interior(&self) -> Option<L0TableCap> {
+        let frame_number = interior_frame_number(self.0)?;
+        let l0_table = ManuallyDrop::new(unsafe { L0TableCap::from_frame_number(frame_number) });
+        Some(L0TableCap::clone(&l0_table))
+    }
+
+    /// Borrow the page mapped by a user leaf entry, along with its
+    /// permissions.
+    fn to_leaf(&self) -> Option<(L1PageCap, Permissions)> {
+        let frame_number = user_leaf_frame_number(self.0)?;
+        let permissions = Permissions::from_bits(self.0)?;
+        let l1_page = ManuallyDrop::new(unsafe { L1PageCap::from_frame_number(frame_number) });
+        Some((L1PageCap::clone(&l1_page), permissions))
+    }
 }
 
 impl L0Entry {
@@ -304,15 +1392,17 @@ impl L0Entry {
         let permissions = permissions.bits();
         const USER: u64 = 0b1 << 4;
         const GLOBAL: u64 = 0b0 << 5;
-        const ACCESSED: u64 = 0b1 << 6;
-        const DIRTY: u64 = 0b1 << 7;
+        // User mode tracks which pages are used by clearing these bits, so we
+        // start them clear.
+        const ACCESSED: u64 = 0b0 << 6;
+        const DIRTY: u64 = 0b0 << 7;
         const RSW: u64 = 0b00 << 8;
         let ppn = (frame_number & ((1 << 44) - 1)) << 10;
         Self(VALID | permissions | USER | GLOBAL | ACCESSED | DIRTY | RSW | ppn)
     }
 
-    pub unsafe fn kernel_leaf(l0_page: InternalPageCap, permissions: Permissions) -> Self {
-        let frame_number = l0_page.into_frame_number().into_raw() as u64;
+    pub unsafe fn kernel_leaf(frame_number: Idx, permissions: Permissions) -> Self {
+        let frame_number = frame_number.into_raw() as u64;
         const VALID: u64 = 0b1 << 0;
         let permissions = permissions.bits();
         const USER: u64 = 0b0 << 4;
@@ -331,11 +1421,339 @@ impl L0Entry {
         Self(VALID | CAP | DONT_CARE)
     }
 
-    pub const fn cap(frame_number: Idx, tag: u8) -> Self {
+    pub const fn cap(frame_number: Idx, tag: u8, rights: Rights) -> Self {
         const VALID: u64 = 0b0 << 0;
         const CAP: u64 = 0b1 << 1;
         let tag: u64 = (tag as u64) << 2;
         let frame_number: u64 = (frame_number.into_raw() as u64) << 10;
-        Self(VALID | CAP | tag | frame_number)
+        let rights: u64 = (rights.0 as u64) << 54;
+        Self(VALID | CAP | tag | frame_number | rights)
+    }
+
+    pub const fn is_invalid(&self) -> bool {
+        const VALID: u64 = 0b1 << 0;
+        const CAP: u64 = 0b1 << 1;
+        self.0 & (VALID | CAP) == 0
+    }
+
+    pub const fn is_leaf(&self) -> bool {
+        const VALID: u64 = 0b1 << 0;
+        self.0 & VALID != 0
+    }
+
+    pub const fn is_cap(&self) -> bool {
+        const VALID: u64 = 0b1 << 0;
+        const CAP: u64 = 0b1 << 1;
+        self.0 & (VALID | CAP) == CAP
+    }
+
+    pub fn node(node: NodeCap, rights: Rights) -> Self {
+        Self::cap(node.into_frame_number(), NodeCap::TAG, rights)
+    }
+
+    /// Decode the rights of a capability entry.
+    fn rights(&self) -> Rights {
+        if !self.is_cap() {
+            return Rights::NONE;
+        }
+        Rights(((self.0 >> 54) & (Rights::ALL.0 as u64)) as u8)
+    }
+
+    /// Decode the frame number and tag of a capability entry.
+    fn cap_parts(&self) -> Option<(Idx, u8)> {
+        if !self.is_cap() {
+            return None;
+        }
+        let tag = ((self.0 >> 2) & 0xff) as u8;
+        // SAFETY: Capabilities are only ever made from valid frame numbers,
+        // or from whatever objects which don't live in frames keep in their
+        // place.
+        let frame_number =
+            unsafe { Idx::from_raw_unchecked(((self.0 >> 10) & ((1 << 44) - 1)) as usize) };
+        Some((frame_number, tag))
+    }
+
+    /// View the entry atomically, since harts may update the accessed and
+    /// dirty bits of leaf entries concurrently, even while we hold the token.
+    fn as_atomic(&mut self) -> &AtomicU64 {
+        // SAFETY: `L0Entry` is a transparent wrapper around `u64`, which has
+        // the same layout as `AtomicU64`, and we hold the only reference to
+        // the entry besides the page table walkers of the harts.
+        unsafe { &*(self as *mut Self).cast::<AtomicU64>() }
+    }
+
+    /// Set the accessed bit of a user leaf entry, and its dirty bit for
+    /// stores, provided that it permits `access`.
+    ///
+    /// The bits may already be set, if the hart faulted on a translation it
+    /// cached before they were.
+    fn update_accessed_dirty(&mut self, access: Access) -> Option<()> {
+        const READ: u64 = 0b1 << 1;
+        const WRITE: u64 = 0b1 << 2;
+        const EXECUTE: u64 = 0b1 << 3;
+        const ACCESSED: u64 = 0b1 << 6;
+        const DIRTY: u64 = 0b1 << 7;
+        let entry = self.as_atomic().load(Relaxed);
+        user_leaf_frame_number(entry)?;
+        let (required, update) = match access {
+            Access::Fetch => (EXECUTE, ACCESSED),
+            Access::Load => (READ, ACCESSED),
+            Access::Store => (WRITE, ACCESSED | DIRTY),
+        };
+        if entry & required == 0 {
+            return None;
+        }
+        // ORDERING: The update only needs to be visible to this hart's page
+        // table walks, which the subsequent fence ensures.
+        self.as_atomic().fetch_or(update, Relaxed);
+        Some(())
+    }
+
+    /// Read and clear the accessed and dirty bits of a user leaf entry,
+    /// returning them in the lowest two bits.
+    fn take_accessed_dirty(&mut self) -> usize {
+        const ACCESSED: u64 = 0b1 << 6;
+        const DIRTY: u64 = 0b1 << 7;
+        if user_leaf_frame_number(self.0).is_none() {
+            return 0x0;
+        }
+        // ORDERING: Harts order their updates to the bits with respect to
+        // their own accesses, and the subsequent fence orders the rest.
+        let entry = self.as_atomic().fetch_and(!(ACCESSED | DIRTY), Relaxed);
+        ((entry & (ACCESSED | DIRTY)) >> 6) as usize
+    }
+
+    /// Borrow the page mapped by a user leaf entry, along with its
+    /// permissions.
+    fn to_leaf(&self) -> Option<(NormalPageCap, Permissions)> {
+        let frame_number = user_leaf_frame_number(self.0)?;
+        let permissions = Permissions::from_bits(self.0)?;
+        // SAFETY: User leaf entries are only constructed from a page's
+        // reference.
+        let l0_page = unsafe { NormalPageCap::from_frame_number(frame_number) };
+        // The entry keeps its own reference, so we only hand out a clone.
+        let l0_page = ManuallyDrop::new(l0_page);
+        Some((NormalPageCap::clone(&l0_page), permissions))
+    }
+
+    /// Borrow the derivation node stored in a capability entry.
+    fn to_node(&self) -> Option<NodeCap> {
+        let (frame_number, tag) = self.cap_parts()?;
+        if tag != NodeCap::TAG {
+            return None;
+        }
+        // SAFETY: Node entries are only constructed by `L0Entry::node`.
+        let node = unsafe { NodeCap::from_frame_number(frame_number) };
+        // The entry keeps its own reference, so we only hand out a clone.
+        let node = ManuallyDrop::new(node);
+        Some(NodeCap::clone(&node))
+    }
+
+    /// Borrow the capability stored in a capability entry, looking through
+    /// its derivation node if it has one.
+    fn to_cap(&self, token: &Token) -> Option<Cap> {
+        if let Some(node) = self.to_node() {
+            return Some(node.node.borrow(token).cap.clone());
+        }
+        let (frame_number, tag) = self.cap_parts()?;
+        // SAFETY: Capability entries are only constructed by `Cap::l0_entry`.
+        let cap = unsafe { Cap::from_l0_entry(frame_number, tag) };
+        // The entry keeps its own reference, so we only hand out a clone.
+        let cap = ManuallyDrop::new(cap);
+        Some(Cap::clone(&cap))
+    }
+}
+
+/// The tables which have died on this hart, and are waiting to be destroyed.
+#[thread_local]
+static DEAD_TABLES: Cell<DeadTables> = Cell::new(DeadTables {
+    l2: None,
+    l1: None,
+    l0: None,
+});
+
+/// A list of dead tables for each type of table, linked through their frames.
+#[derive(Clone, Copy)]
+struct DeadTables {
+    l2: Option<Idx>,
+    l1: Option<Idx>,
+    l0: Option<Idx>,
+}
+
+trait TableEntry: Sized {
+    /// The list of dead tables of this entry's type.
+    fn dead_tables(tables: &mut DeadTables) -> &mut Option<Idx>;
+
+    /// Prepare the entries of a dead table to be dropped.
+    fn detach(_token: &mut Token, _entries: &TokenCell<[Self; TABLE_LEN]>) {}
+}
+
+impl TableEntry for L2Entry {
+    fn dead_tables(tables: &mut DeadTables) -> &mut Option<Idx> {
+        &mut tables.l2
+    }
+}
+
+impl TableEntry for L1Entry {
+    fn dead_tables(tables: &mut DeadTables) -> &mut Option<Idx> {
+        &mut tables.l1
+    }
+}
+
+impl TableEntry for L0Entry {
+    fn dead_tables(tables: &mut DeadTables) -> &mut Option<Idx> {
+        &mut tables.l0
+    }
+
+    /// Unlink the derivation nodes held by the table's slots, which don't
+    /// keep the table alive, and so must not outlive it in their trees.
+    fn detach(token: &mut Token, entries: &TokenCell<[Self; TABLE_LEN]>) {
+        for index in 0..TABLE_LEN {
+            if let Some(node) = entries.borrow(token)[index].to_node() {
+                node.unlink(token);
+                node.node.borrow_mut(token).l0_table = None;
+            }
+        }
+    }
+}
+
+/// Release a reference to a table, setting the table aside to be destroyed if
+/// it was the last.
+fn release_table<E: TableEntry>(entries: NormalArc<TokenCell<[E; TABLE_LEN]>>) {
+    let mut tables = DEAD_TABLES.get();
+    let dead_tables = E::dead_tables(&mut tables);
+    match entries.bury(*dead_tables) {
+        Ok(frame_number) => *dead_tables = Some(frame_number),
+        // The table is still referenced elsewhere.
+        Err(entries) => return drop(entries),
+    }
+    DEAD_TABLES.set(tables);
+}
+
+/// Destroy every table which has died on this hart.
+///
+/// Destroying a table may kill more, which are destroyed one after the other
+/// rather than recursively. Only the objects between an entry and the tables
+/// it releases remain on the stack. A hart must destroy its dead tables before
+/// it gives up the token, so that no other hart can reach a dead table through
+/// a derivation node.
+pub fn destroy_dead_tables(token: &mut Token) {
+    while destroy_dead_table::<L0Entry>(token)
+        || destroy_dead_table::<L1Entry>(token)
+        || destroy_dead_table::<L2Entry>(token)
+    {}
+}
+
+/// Destroy one of the dead tables with entries of type `E`, returning whether
+/// there was one.
+fn destroy_dead_table<E: TableEntry>(token: &mut Token) -> bool {
+    let mut tables = DEAD_TABLES.get();
+    let dead_tables = E::dead_tables(&mut tables);
+    let frame_number = match *dead_tables {
+        Some(frame_number) => frame_number,
+        None => return false,
+    };
+    // SAFETY: Each list only holds tables buried by `release_table` with
+    // entries of its type.
+    let (entries, next) = unsafe { NormalArc::<TokenCell<[E; TABLE_LEN]>>::exhume(frame_number) };
+    *dead_tables = next;
+    DEAD_TABLES.set(tables);
+    E::detach(token, &entries);
+    // This releases the table's entries, which may set aside more tables.
+    drop(entries);
+    true
+}
+
+impl Drop for L2TableCap {
+    fn drop(&mut self) {
+        // SAFETY: The table is never touched again.
+        release_table(unsafe { ManuallyDrop::take(&mut self.entries) });
+    }
+}
+
+impl Drop for L1TableCap {
+    fn drop(&mut self) {
+        // SAFETY: The table is never touched again.
+        release_table(unsafe { ManuallyDrop::take(&mut self.entries) });
+    }
+}
+
+impl Drop for L0TableCap {
+    fn drop(&mut self) {
+        // SAFETY: The table is never touched again.
+        release_table(unsafe { ManuallyDrop::take(&mut self.entries) });
+    }
+}
+
+impl Drop for L2Entry {
+    fn drop(&mut self) {
+        if let Some(frame_number) = interior_frame_number(self.0) {
+            // SAFETY: Interior entries are only constructed from a L1 table's
+            // reference.
+            drop(unsafe { L1TableCap::from_frame_number(frame_number) });
+        } else if let Some(frame_number) = user_leaf_frame_number(self.0) {
+            // SAFETY: User leaf entries are only constructed from a page's
+            // reference.
+            drop(unsafe { L2PageCap::from_frame_number(frame_number) });
+        } else if let Some(asid) = self.to_asid() {
+            // ORDERING: This use of the ASID must happen strictly before any
+            // future one.
+            let (word, bit) = asid_bit(asid);
+            word.fetch_and(!bit, Release);
+        }
+    }
+}
+
+impl Drop for L1Entry {
+    fn drop(&mut self) {
+        if let Some(frame_number) = interior_frame_number(self.0) {
+            // SAFETY: Interior entries are only constructed from a L0 table's
+            // reference.
+            drop(unsafe { L0TableCap::from_frame_number(frame_number) });
+        } else if let Some(frame_number) = user_leaf_frame_number(self.0) {
+            // SAFETY: User leaf entries are only constructed from a page's
+            // reference.
+            drop(unsafe { L1PageCap::from_frame_number(frame_number) });
+        }
+    }
+}
+
+impl Drop for L0Entry {
+    fn drop(&mut self) {
+        if let Some((frame_number, tag)) = self.cap_parts() {
+            if tag == NodeCap::TAG {
+                // SAFETY: Node entries are only constructed by `L0Entry::node`.
+                drop(unsafe { NodeCap::from_frame_number(frame_number) });
+            } else {
+                // SAFETY: Capability entries are only constructed by
+                // `Cap::l0_entry`.
+                drop(unsafe { Cap::from_l0_entry(frame_number, tag) });
+            }
+        } else if let Some(frame_number) = user_leaf_frame_number(self.0) {
+            // SAFETY: User leaf entries are only constructed from a page's
+            // reference.
+            drop(unsafe { NormalPageCap::from_frame_number(frame_number) });
+        }
+    }
+}
+
+/// Extract the frame number from a valid, non-leaf entry.
+fn interior_frame_number(entry: u64) -> Option<Idx> {
+    const VALID: u64 = 0b1 << 0;
+    const PERMISSIONS: u64 = 0b111 << 1;
+    if entry & (VALID | PERMISSIONS) != VALID {
+        return None;
+    }
+    Idx::from_raw(((entry >> 10) & ((1 << 44) - 1)) as usize)
+}
+
+/// Extract the frame number from a valid, non-global leaf entry.
+fn user_leaf_frame_number(entry: u64) -> Option<Idx> {
+    const VALID: u64 = 0b1 << 0;
+    const PERMISSIONS: u64 = 0b111 << 1;
+    const GLOBAL: u64 = 0b1 << 5;
+    if entry & (VALID | GLOBAL) != VALID || entry & PERMISSIONS == 0 {
+        return None;
     }
+    Idx::from_raw(((entry >> 10) & ((1 << 44) - 1)) as usize)
 }
@@ -1,8 +1,10 @@
 use {
     crate::{
-        frame::{NormalArc, Idx},
+        frame::{Idx, NormalArc, PartialSpan},
+        sched,
         sync::{Token, TokenCell},
-        table::L2TableCap,
+        syscall::Error,
+        table::{destroy_dead_tables, L2TableCap},
     },
     ::core::{
         fmt::{Debug, Formatter, Result as FmtResult},
@@ -12,15 +14,52 @@ use {
 
 impl CallCap {
     pub fn new(frame_number: Idx, pc: usize, sp: usize, l2_table: L2TableCap) -> Option<Self> {
-        let call = Call { pc, sp, l2_table };
+        let call = Call {
+            pc,
+            sp,
+            l2_table,
+            badge: 0x0,
+        };
         let call = TokenCell::new(call);
         let call = NormalArc::new(frame_number, call)?;
         Some(Self { call })
     }
 
+    /// Create a copy of this call which delivers `badge` to the callee, so
+    /// that it can tell its callers apart.
+    ///
+    /// Only unbadged calls can be badged, and the badge must be nonzero.
+    pub fn badged(&self, token: &Token, frame_number: Idx, badge: usize) -> Result<Self, Error> {
+        let call = self.call.borrow(token);
+        if call.badge != 0x0 || badge == 0x0 {
+            return Err(Error::InvalidArgument);
+        }
+        let call = Call {
+            pc: call.pc,
+            sp: call.sp,
+            l2_table: call.l2_table.clone(),
+            badge,
+        };
+        let call = TokenCell::new(call);
+        let call = NormalArc::new(frame_number, call).ok_or(Error::InvalidFrame)?;
+        Ok(Self { call })
+    }
+
+    pub fn badge(&self, token: &Token) -> usize {
+        self.call.borrow(token).badge
+    }
+
     pub fn into_frame_number(self) -> Idx {
         self.call.into_raw()
     }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let call = unsafe { NormalArc::from_raw(frame_number) };
+        Self { call }
+    }
 }
 
 #[derive(Clone)]
@@ -33,6 +72,8 @@ struct Call {
     pc: usize,
     sp: usize,
     l2_table: L2TableCap,
+    /// The word delivered to the callee, or zero if the call is unbadged.
+    badge: usize,
 }
 
 impl ThreadCap {
@@ -42,6 +83,10 @@ impl ThreadCap {
             l2_table,
             call_stack: CallStack::empty(),
             exception_call: None,
+            next: None,
+            ipc_state: IpcState::Running,
+            reply: None,
+            partial_span: None,
         };
         let thread = TokenCell::new(thread);
         let thread = NormalArc::new(frame_number, thread)?;
@@ -53,39 +98,94 @@ impl ThreadCap {
         thread.exception_call = Some(call);
     }
 
-    pub fn call_exception(&self, token: &mut Token) -> Option<()> {
-        let thread = self.thread.borrow_mut(token);
-        let exception_call = thread.exception_call.clone()?;
-        self.call(token, &exception_call)
+    /// Enter the thread's exception call on behalf of the code which just
+    /// trapped with `scause` and `stval`.
+    ///
+    /// The handler gets the call's badge in `a1`, `scause` in `a2`, `stval` in
+    /// `a3` and the trapping pc in `a4`, with every other register cleared.
+    /// The trapping code's registers are saved in full, and restored when the
+    /// handler returns, so that the trapping instruction is tried again.
+    pub fn call_exception(&self, token: &mut Token, scause: u64, stval: u64) -> Result<(), Error> {
+        let thread = self.thread.borrow(token);
+        let exception_call = thread.exception_call.clone().ok_or(Error::InvalidState)?;
+        let pc = thread.context.as_ref().ok_or(Error::InvalidState)?.pc;
+        let (badge, context) = self.enter(token, &exception_call, true)?;
+        context.a[0x1..0x5].copy_from_slice(&[badge, scause as usize, stval as usize, pc]);
+        Ok(())
+    }
+
+    /// Enter `call`, returning its badge, which the caller is responsible for
+    /// delivering to the callee.
+    ///
+    /// The argument registers pass through to the callee. Every other register
+    /// the caller expects to be preserved is saved until the callee returns,
+    /// and cleared along with the temporaries, so that the callee learns
+    /// nothing from them.
+    pub fn call(&self, token: &mut Token, call: &CallCap) -> Result<usize, Error> {
+        let args = self.context(token).ok_or(Error::InvalidState)?.a;
+        let (badge, context) = self.enter(token, call, false)?;
+        context.a = args;
+        Ok(badge)
     }
 
-    pub fn call(&self, token: &mut Token, call: &CallCap) -> Option<()> {
+    /// Save the thread's context and enter `call` with a clear one, returning
+    /// the call's badge and the new context.
+    fn enter<'token>(
+        &'token self,
+        token: &'token mut Token,
+        call: &CallCap,
+        exception: bool,
+    ) -> Result<(usize, &'token mut Context), Error> {
         let call = call.call.borrow(token);
         let pc = call.pc;
         let sp = call.sp;
         let l2_table = call.l2_table.clone();
+        let badge = call.badge;
 
         let thread = self.thread.borrow_mut(token);
-        let context = thread.context.as_mut()?;
-        thread.call_stack.push(Call {
-            pc: context.pc,
-            sp: context.sp,
+        let context = thread.context.as_mut().ok_or(Error::InvalidState)?;
+        let frame = ReturnFrame {
+            context: context.clone(),
             l2_table: thread.l2_table.clone(),
-        })?;
-        context.pc = pc;
-        context.sp = sp;
+            exception,
+        };
+        thread.call_stack.push(frame).ok_or(Error::CallStackFull)?;
+        *context = Context {
+            pc,
+            sp,
+            ..Default::default()
+        };
         thread.l2_table = l2_table;
-        Some(())
+        Ok((badge, context))
     }
 
-    pub fn ret(&mut self, token: &mut Token) -> Option<()> {
+    /// Return from the innermost call, restoring the caller's registers.
+    ///
+    /// As with [`Self::call`], the argument registers pass through to the
+    /// caller, while the temporaries are cleared. Returning from an exception
+    /// call instead restores every register, and returns true so that the
+    /// caller leaves them be.
+    pub fn ret(&self, token: &mut Token) -> Result<bool, Error> {
         let thread = self.thread.borrow_mut(token);
-        let context = thread.context.as_mut()?;
-        let call = thread.call_stack.pop()?;
-        context.pc = call.pc;
-        context.sp = call.sp;
-        thread.l2_table = call.l2_table;
-        Some(())
+        let context = thread.context.as_mut().ok_or(Error::InvalidState)?;
+        let frame = thread.call_stack.pop().ok_or(Error::InvalidState)?;
+        let exception = frame.exception;
+        *context = if exception {
+            frame.context
+        } else {
+            Context {
+                pc: frame.context.pc,
+                sp: frame.context.sp,
+                ra: frame.context.ra,
+                gp: frame.context.gp,
+                tp: frame.context.tp,
+                s: frame.context.s,
+                a: context.a,
+                ..Default::default()
+            }
+        };
+        thread.l2_table = frame.l2_table;
+        Ok(exception)
     }
 
     pub fn context<'token>(&'token self, token: &'token Token) -> Option<&'token Context> {
@@ -103,6 +203,59 @@ impl ThreadCap {
         &self.thread.borrow(token).l2_table
     }
 
+    /// Take the thread queued after this one, if any.
+    pub fn take_next(&self, token: &mut Token) -> Option<ThreadCap> {
+        self.thread.borrow_mut(token).next.take()
+    }
+
+    /// Queue `next` after this thread.
+    pub fn set_next(&self, token: &mut Token, next: ThreadCap) {
+        self.thread.borrow_mut(token).next = Some(next);
+    }
+
+    pub fn ipc_state(&self, token: &Token) -> IpcState {
+        self.thread.borrow(token).ipc_state
+    }
+
+    pub fn set_ipc_state(&self, token: &mut Token, ipc_state: IpcState) {
+        self.thread.borrow_mut(token).ipc_state = ipc_state;
+    }
+
+    /// Take the caller waiting for this thread's reply, if any.
+    pub fn take_reply(&self, token: &mut Token) -> Option<ThreadCap> {
+        self.thread.borrow_mut(token).reply.take()
+    }
+
+    /// Make `caller` the caller waiting for this thread's reply, returning the
+    /// caller which was waiting before, if any.
+    pub fn set_reply(&self, token: &mut Token, caller: ThreadCap) -> Option<ThreadCap> {
+        self.thread.borrow_mut(token).reply.replace(caller)
+    }
+
+    /// Take the span the thread was building a page from, if any.
+    pub fn take_partial_span(&self, token: &mut Token) -> Option<PartialSpan> {
+        self.thread.borrow_mut(token).partial_span.take()
+    }
+
+    /// Keep `partial_span` for the thread until it retries building a page
+    /// from it.
+    pub fn set_partial_span(&self, token: &mut Token, partial_span: PartialSpan) {
+        self.thread.borrow_mut(token).partial_span = Some(partial_span);
+    }
+
+    /// Deliver `result` to the thread, which was waiting off the run queue,
+    /// and put it back on the run queue.
+    pub fn wake(&self, token: &mut Token, result: Result<usize, Error>) {
+        let context = self
+            .context_mut(token)
+            .expect("A waiting thread should have a context.");
+        (context.a[0x0], context.a[0x1]) = match result {
+            Ok(value) => (0x0, value),
+            Err(error) => (error as usize, 0x0),
+        };
+        sched::push(token, self.clone());
+    }
+
     pub fn resume(&self, mut token: Token) -> Result<(Token, u64, u64), Token> {
         let thread = self.thread.borrow_mut(&mut token);
         let mut context = if let Some(context) = thread.context.take() {
@@ -111,7 +264,9 @@ impl ThreadCap {
             return Err(token);
         };
         let l2_table = thread.l2_table.clone();
-        l2_table.activate();
+        l2_table.activate(&token);
+        // The table which was active may have died along the way.
+        destroy_dead_tables(&mut token);
         token.release();
 
         let (scause, stval) = unsafe { crate::plat::resume(&mut context) };
@@ -126,6 +281,14 @@ impl ThreadCap {
     pub fn into_frame_number(self) -> Idx {
         self.thread.into_raw()
     }
+
+    /// # Safety
+    /// `frame_number` must have been returned from a previous call to
+    /// `into_frame_number`.
+    pub unsafe fn from_frame_number(frame_number: Idx) -> Self {
+        let thread = unsafe { NormalArc::from_raw(frame_number) };
+        Self { thread }
+    }
 }
 
 #[derive(Clone)]
@@ -138,27 +301,60 @@ struct Thread {
     l2_table: L2TableCap,
     call_stack: CallStack,
     exception_call: Option<CallCap>,
+    /// The thread after this one in the run queue, or in the queue of the
+    /// endpoint it's waiting on.
+    next: Option<ThreadCap>,
+    ipc_state: IpcState,
+    /// The caller waiting for this thread's reply, if any.
+    reply: Option<ThreadCap>,
+    /// The span the thread is building a page from, which is released if the
+    /// thread does anything else, or is destroyed, before it's built.
+    partial_span: Option<PartialSpan>,
+}
+
+/// What a thread is waiting for on an endpoint, if anything.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpcState {
+    Running,
+    /// Waiting for a receiver, to deliver `badge` along with its message, and
+    /// then wait for a reply if `call`.
+    Sending {
+        badge: usize,
+        call: bool,
+    },
+    Receiving,
+    AwaitingReply,
+}
+
+/// What a call must restore when it returns to its caller.
+struct ReturnFrame {
+    /// The caller's context as it was when it made the call.
+    context: Context,
+    l2_table: L2TableCap,
+    /// Whether the call was made on behalf of trapping code, which expects
+    /// every register to be restored.
+    exception: bool,
 }
 
 impl CallStack {
     const MAX_DEPTH: usize = 8;
 
     pub fn empty() -> Self {
-        const CALL_INIT: MaybeUninit<Call> = MaybeUninit::uninit();
+        const CALL_INIT: MaybeUninit<ReturnFrame> = MaybeUninit::uninit();
         Self {
             calls: [CALL_INIT; Self::MAX_DEPTH],
             depth: 0,
         }
     }
 
-    pub fn push(&mut self, call: Call) -> Option<&mut Call> {
+    pub fn push(&mut self, call: ReturnFrame) -> Option<&mut ReturnFrame> {
         let c = self.calls.get_mut(self.depth)?;
         let c = c.write(call);
         self.depth += 1;
         Some(c)
     }
 
-    pub fn pop(&mut self) -> Option<Call> {
+    pub fn pop(&mut self) -> Option<ReturnFrame> {
         let index = self.depth.checked_sub(1)?;
         let c = &mut self.calls[index];
         // SAFETY: All elements prior to `self.depth` are initialized, and
@@ -170,10 +366,19 @@ impl CallStack {
 }
 
 struct CallStack {
-    calls: [MaybeUninit<Call>; Self::MAX_DEPTH],
+    calls: [MaybeUninit<ReturnFrame>; Self::MAX_DEPTH],
     depth: usize,
 }
 
+impl Drop for CallStack {
+    fn drop(&mut self) {
+        // A thread destroyed mid-call still holds its callers' frames.
+        while let Some(call) = self.pop() {
+            drop(call);
+        }
+    }
+}
+
 pub const SSTATUS_SPP_MASK: u64 = 0x100u64;
 
 /// General purpose register context for a hart.
@@ -0,0 +1,47 @@
+//! The supervisor timer, which we program either directly through the Sstc
+//! extension's `stimecmp` or, failing that, through SBI.
+
+use {
+    crate::{
+        plat,
+        sbi::{base, time},
+    },
+    ::core::sync::atomic::{AtomicBool, Ordering::Relaxed},
+};
+
+static SSTC: AtomicBool = AtomicBool::new(false);
+
+/// Determine how to program the timer.
+///
+/// # Safety
+/// Must be called during boot, before any other harts are started, and with no
+/// traps being taken from supervisor mode.
+pub unsafe fn probe() {
+    // We assume the harts are homogeneous, so it's enough to probe this one.
+    let sstc = unsafe { plat::probe_sstc() };
+    if sstc {
+        kernel!("Using Sstc for the supervisor timer.");
+    } else {
+        let time = base::probe_extension(time::EID);
+        assert!(matches!(time, base::ExtAvail::Available(_)));
+        kernel!("Using SBI for the supervisor timer.");
+    }
+    SSTC.store(sstc, Relaxed);
+}
+
+/// Raise a supervisor timer interrupt on the current hart once the real-time
+/// counter reaches `deadline`, replacing any previous deadline and clearing any
+/// pending timer interrupt.
+pub fn set_deadline(deadline: u64) {
+    if SSTC.load(Relaxed) {
+        // SAFETY: We probed that the harts implement Sstc.
+        unsafe { plat::set_stimecmp(deadline) };
+    } else {
+        time::set_timer(deadline).expect("Setting the timer should never fail.");
+    }
+}
+
+/// Stop raising supervisor timer interrupts on the current hart.
+pub fn clear_deadline() {
+    set_deadline(u64::MAX)
+}
@@ -1,6 +1,162 @@
+//! The kernel's system call interface.
+//!
+//! Mirrors the definitions in the kernel's `syscall` module.
+//!
+//! Calling a call capability passes the six argument registers, `a2` through
+//! `a7`, through to the callee untouched, and returning passes them back to the
+//! caller the same way. `a0` and `a1` carry the invocation itself, so they
+//! never pass through: the callee gets zero in `a0` and the call's badge in
+//! `a1`, and the caller gets zero in both. Every other register is cleared for
+//! the callee, and those the caller expects to be preserved, `ra`, `sp`, `gp`,
+//! `tp` and `s0` through `s11`, are restored once the callee returns. Returning
+//! from an exception call instead restores every register of the code which
+//! trapped.
+
+/// The capability pointer which designates the debugging operations.
+pub const NULL: usize = 0x0;
+
+/// Capability pointers to the slots the kernel fills in for the root thread.
+pub mod root {
+    pub const L2_TABLE: usize = 0x8000_0000;
+    pub const L1_TABLE: usize = 0x8000_1000;
+    pub const L0_TABLE: usize = 0x8000_2000;
+    pub const THREAD: usize = 0x8000_3000;
+    pub const CONSOLE: usize = 0x8000_4000;
+    pub const IRQ_CONTROL: usize = 0x8000_5000;
+}
+
+/// Operation labels, namespaced by the type of the invoked capability.
+pub mod label {
+    pub const NULL_PUT: usize = 0x1;
+    pub const NULL_RETURN: usize = 0x2;
+
+    pub const L2_TABLE_MAP_L1_TABLE: usize = 0x0;
+    pub const L2_TABLE_UNMAP_L1_TABLE: usize = 0x1;
+    pub const L2_TABLE_MAP_L2_PAGE: usize = 0x2;
+    pub const L2_TABLE_UNMAP_L2_PAGE: usize = 0x3;
+
+    pub const L1_TABLE_MAP_L0_TABLE: usize = 0x0;
+    pub const L1_TABLE_UNMAP_L0_TABLE: usize = 0x1;
+    pub const L1_TABLE_MAP_L1_PAGE: usize = 0x2;
+    pub const L1_TABLE_UNMAP_L1_PAGE: usize = 0x3;
+
+    pub const L0_TABLE_MAP_L0_PAGE: usize = 0x0;
+    pub const L0_TABLE_NEW_L2_TABLE: usize = 0x1;
+    pub const L0_TABLE_NEW_L1_TABLE: usize = 0x2;
+    pub const L0_TABLE_NEW_L0_TABLE: usize = 0x3;
+    pub const L0_TABLE_NEW_L0_PAGE: usize = 0x4;
+    pub const L0_TABLE_NEW_CALL: usize = 0x5;
+    pub const L0_TABLE_COPY: usize = 0x6;
+    pub const L0_TABLE_MOVE: usize = 0x7;
+    pub const L0_TABLE_DELETE: usize = 0x8;
+    pub const L0_TABLE_DERIVE: usize = 0x9;
+    pub const L0_TABLE_REVOKE: usize = 0xa;
+    pub const L0_TABLE_MINT: usize = 0xb;
+    pub const L0_TABLE_BADGE: usize = 0xc;
+    pub const L0_TABLE_UNMAP_L0_PAGE: usize = 0xd;
+    pub const L0_TABLE_NEW_L2_PAGE: usize = 0xe;
+    pub const L0_TABLE_NEW_L1_PAGE: usize = 0xf;
+    pub const L0_TABLE_TAKE_ACCESSED_DIRTY: usize = 0x10;
+    pub const L0_TABLE_NEW_NOTIFICATION: usize = 0x11;
+    pub const L0_TABLE_NEW_ENDPOINT: usize = 0x12;
+
+    pub const THREAD_SET_EXCEPTION_CALL: usize = 0x0;
+
+    pub const CALL_CALL: usize = 0x0;
+
+    pub const CONSOLE_POLL: usize = 0x0;
+    pub const CONSOLE_SHUTDOWN: usize = 0x1;
+
+    pub const IRQ_CONTROL_GET_HANDLER: usize = 0x0;
+
+    pub const IRQ_HANDLER_WAIT: usize = 0x0;
+    pub const IRQ_HANDLER_POLL: usize = 0x1;
+    pub const IRQ_HANDLER_ACK: usize = 0x2;
+    pub const IRQ_HANDLER_SET_NOTIFICATION: usize = 0x3;
+
+    pub const NOTIFICATION_SIGNAL: usize = 0x0;
+    pub const NOTIFICATION_WAIT: usize = 0x1;
+    pub const NOTIFICATION_POLL: usize = 0x2;
+    pub const NOTIFICATION_SET_TIMER: usize = 0x3;
+
+    pub const ENDPOINT_SEND: usize = 0x0;
+    pub const ENDPOINT_CALL: usize = 0x1;
+    pub const ENDPOINT_RECV: usize = 0x2;
+    pub const ENDPOINT_REPLY: usize = 0x3;
+    pub const ENDPOINT_REPLY_RECV: usize = 0x4;
+}
+
+/// Error codes returned by the kernel.
+pub mod error {
+    pub const INVALID_CAPABILITY: usize = 0x1;
+    pub const INVALID_LABEL: usize = 0x2;
+    pub const INVALID_ARGUMENT: usize = 0x3;
+    pub const INVALID_FRAME: usize = 0x4;
+    pub const OCCUPIED: usize = 0x5;
+    pub const INVALID_STATE: usize = 0x6;
+    pub const INVALID_ADDRESS: usize = 0x7;
+    pub const MISSING_TABLE: usize = 0x8;
+    pub const EMPTY_SLOT: usize = 0x9;
+    pub const DERIVED: usize = 0xa;
+    pub const NOT_DERIVED: usize = 0xb;
+    pub const HAS_CHILDREN: usize = 0xc;
+    pub const INSUFFICIENT_RIGHTS: usize = 0xd;
+    pub const NOT_MAPPED: usize = 0xe;
+    pub const INVALID_ASID: usize = 0xf;
+    pub const WOULD_BLOCK: usize = 0x10;
+    pub const CALL_STACK_FULL: usize = 0x11;
+}
+
+/// The rights a capability slot can confer.
+pub mod rights {
+    pub const MAP_READ: usize = 0b1 << 0;
+    pub const MAP_WRITE: usize = 0b1 << 1;
+    pub const MAP_EXECUTE: usize = 0b1 << 2;
+    pub const GRANT: usize = 0b1 << 3;
+    pub const CONTROL: usize = 0b1 << 4;
+
+    pub const NONE: usize = 0x0;
+    pub const MAP_ALL: usize = MAP_READ | MAP_WRITE | MAP_EXECUTE;
+    pub const ALL: usize = MAP_ALL | GRANT | CONTROL;
+}
+
+/// Invoke the capability at `cap` with the operation `label`.
+///
+/// Every argument register and temporary is treated as clobbered, since
+/// receiving a message or making a call may overwrite them.
+///
+/// # Safety
+/// `cap` and `label` must name an operation which leaves the memory the
+/// calling code relies on, such as its own code, stack and data, mapped as it
+/// was, and which doesn't otherwise change the thread's state behind the
+/// compiler's back.
 #[inline(always)]
-pub unsafe fn call(a0: usize, a1: usize) {
+pub unsafe fn invoke(cap: usize, label: usize, args: [usize; 6]) -> Result<usize, usize> {
+    let error: usize;
+    let value: usize;
     unsafe {
-        core::arch::asm!("ecall", inout("a0") a0 => _, inout("a1") a1 => _);
+        core::arch::asm!(
+            "ecall",
+            inout("a0") cap => error,
+            inout("a1") label => value,
+            inout("a2") args[0] => _,
+            inout("a3") args[1] => _,
+            inout("a4") args[2] => _,
+            inout("a5") args[3] => _,
+            inout("a6") args[4] => _,
+            inout("a7") args[5] => _,
+            out("t0") _,
+            out("t1") _,
+            out("t2") _,
+            out("t3") _,
+            out("t4") _,
+            out("t5") _,
+            out("t6") _,
+        );
+    }
+    if error == 0x0 {
+        Ok(value)
+    } else {
+        Err(error)
     }
 }
@@ -23,8 +23,8 @@ pub fn main() -> ! {
         let mut bytes = [b' '; size_of::<usize>()];
         bytes[..chunk.len()].copy_from_slice(chunk);
         let value = usize::from_be_bytes(bytes);
-        unsafe { abi::call(0x1, value) };
+        let _ = unsafe { abi::invoke(abi::NULL, abi::label::NULL_PUT, [value, 0, 0, 0, 0, 0]) };
     }
-    unsafe { abi::call(0x0, 0x0) };
+    let _ = unsafe { abi::invoke(abi::root::CONSOLE, abi::label::CONSOLE_SHUTDOWN, [0x0; 6]) };
     loop {}
 }
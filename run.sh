@@ -13,7 +13,7 @@ cargo objcopy \
 qemu-system-riscv64 \
     -machine virt \
     -m 6G \
-    -smp 1 \
+    -smp 4 \
     -bios default \
     -kernel system_image \
     -d guest_errors \